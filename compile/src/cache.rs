@@ -0,0 +1,179 @@
+//! A cache of the inputs each ISPC source was last compiled with, used to skip
+//! recompiling sources which haven't changed. Content hashes are used instead of
+//! modification times, which aren't reliable e.g. on CI after a fresh checkout.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+
+use crate::manifest::json_string;
+
+/// The hashes of the inputs an ISPC source was compiled with
+#[derive(PartialEq, Eq)]
+pub struct Entry {
+    /// Hash of the contents of the source file and each file it includes
+    pub inputs: u64,
+    /// Hash of the arguments the source was compiled with and the compiler version
+    pub args: u64,
+}
+
+/// The cache file written to the build directory, mapping the name each source is
+/// compiled to, to the hashes of the inputs it was compiled with.
+pub struct Cache {
+    path: PathBuf,
+    entries: BTreeMap<String, Entry>,
+}
+
+impl Cache {
+    /// Load the cache from `path`, if the file is missing or can't be read the
+    /// cache starts out empty.
+    pub fn load(path: &Path) -> Cache {
+        let re = Regex::new(
+            r#"^\s*("(?:[^"\\]|\\.)*"): \{ "inputs": "([0-9a-f]{16})", "args": "([0-9a-f]{16})" \},?$"#,
+        )
+        .unwrap();
+        let mut entries = BTreeMap::new();
+        if let Ok(contents) = fs::read_to_string(path) {
+            for c in contents.lines().filter_map(|l| re.captures(l)) {
+                entries.insert(
+                    c[1].to_string(),
+                    Entry {
+                        inputs: u64::from_str_radix(&c[2], 16).unwrap(),
+                        args: u64::from_str_radix(&c[3], 16).unwrap(),
+                    },
+                );
+            }
+        }
+        Cache {
+            path: path.to_path_buf(),
+            entries,
+        }
+    }
+    /// Get the inputs `name` was last compiled with
+    pub fn get(&self, name: &str) -> Option<&Entry> {
+        self.entries.get(&json_string(name))
+    }
+    /// Record the inputs `name` was compiled with
+    pub fn insert(&mut self, name: &str, entry: Entry) {
+        self.entries.insert(json_string(name), entry);
+    }
+    /// Write the cache back out to the file it was loaded from
+    pub fn save(&self) -> std::io::Result<()> {
+        let entries = self
+            .entries
+            .iter()
+            .map(|(name, e)| {
+                format!(
+                    "  {}: {{ \"inputs\": \"{:016x}\", \"args\": \"{:016x}\" }}",
+                    name, e.inputs, e.args
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",\n");
+        fs::write(&self.path, format!("{{\n{entries}\n}}\n"))
+    }
+}
+
+/// Hash the contents of the source file and each dependency listed in the ISPC
+/// dependency file `deps`. Returns None if the dependency file can't be read.
+pub fn hash_inputs(src: &Path, deps: &Path) -> Option<u64> {
+    let deps = fs::File::open(deps).ok()?;
+    let mut hash = Fnv::new();
+    hash.write(&fs::read(src).ok()?);
+    for d in BufReader::new(deps).lines() {
+        let d = d.ok()?;
+        hash.write(d.as_bytes());
+        // A missing dependency, e.g. a deleted include, still changes the hash
+        match fs::read(&d) {
+            Ok(contents) => hash.write(&contents),
+            Err(_) => hash.write(b"\0missing"),
+        }
+    }
+    Some(hash.finish())
+}
+
+/// Hash the contents of each of the `files`, returns None if one can't be read.
+pub fn hash_files(files: &[PathBuf]) -> Option<u64> {
+    let mut hash = Fnv::new();
+    for f in files {
+        hash.write(f.as_os_str().as_encoded_bytes());
+        hash.write(b"\0");
+        hash.write(&fs::read(f).ok()?);
+    }
+    Some(hash.finish())
+}
+
+/// Hash the arguments passed to ISPC
+pub fn hash_args<S: AsRef<str>>(args: &[S]) -> u64 {
+    let mut hash = Fnv::new();
+    for a in args {
+        hash.write(a.as_ref().as_bytes());
+        hash.write(b"\0");
+    }
+    hash.finish()
+}
+
+/// The 64-bit FNV-1a hash, which unlike the std hasher is stable across
+/// Rust releases so the cache stays valid when the toolchain is updated.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Fnv {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= u64::from(*b);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn round_trip() {
+        let dir = env::temp_dir().join(format!("ispc-rs-cache-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(".ispc-cache.json");
+        let mut cache = Cache::load(&path);
+        assert!(cache.get("foo_ispc").is_none());
+        let entry = Entry {
+            inputs: 0x0123_4567_89ab_cdef,
+            args: 42,
+        };
+        cache.insert("foo_ispc", entry);
+        cache.insert(
+            "quoted \"name\"",
+            Entry {
+                inputs: 1,
+                args: u64::MAX,
+            },
+        );
+        cache.save().unwrap();
+
+        let loaded = Cache::load(&path);
+        let foo = loaded.get("foo_ispc").unwrap();
+        assert_eq!((foo.inputs, foo.args), (0x0123_4567_89ab_cdef, 42));
+        let quoted = loaded.get("quoted \"name\"").unwrap();
+        assert_eq!((quoted.inputs, quoted.args), (1, u64::MAX));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hash_args_separates_arguments() {
+        assert_ne!(hash_args(&["-O2", "-g"]), hash_args(&["-O2-g"]));
+        assert_eq!(hash_args(&["-O2", "-g"]), hash_args(&["-O2", "-g"]));
+    }
+}
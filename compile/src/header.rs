@@ -0,0 +1,173 @@
+//! Helpers for reading the C headers generated by ISPC, which we use to find
+//! out what a compiled ISPC file exports.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use regex::Regex;
+
+/// The signature of a function exported from ISPC, as declared in the header.
+pub struct ExportedFunction {
+    pub name: String,
+    pub return_type: String,
+    /// The type and name of each parameter.
+    pub params: Vec<(String, String)>,
+}
+
+/// Get the names of the functions exported in the ISPC generated header.
+pub fn exported_functions(header: &Path) -> Vec<String> {
+    exported_signatures(header)
+        .into_iter()
+        .map(|f| f.name)
+        .collect()
+}
+
+/// Get the signatures of the functions exported in the ISPC generated header.
+///
+/// ISPC writes each exported function on its own line in the `extern "C"`
+/// block of the header, e.g. `extern void add_lists(float * a, int32_t n);`
+pub fn exported_signatures(header: &Path) -> Vec<ExportedFunction> {
+    let contents = fs::read_to_string(header)
+        .unwrap_or_else(|e| panic!("Failed to read ISPC header {}: {}", header.display(), e));
+    let re = Regex::new(r"^\s*extern\s+(.*?)\b([A-Za-z_][A-Za-z0-9_]*)\s*\((.*)\)\s*;").unwrap();
+    contents
+        .lines()
+        .filter_map(|l| re.captures(l))
+        .map(|c| ExportedFunction {
+            name: c[2].to_string(),
+            return_type: c[1].trim().to_string(),
+            params: parse_params(&c[3]),
+        })
+        .collect()
+}
+
+/// Get the name declared by the C declaration `decl`, e.g. `table` for
+/// `float table[256]`.
+pub fn declared_name(decl: &str) -> Option<String> {
+    let decl = decl.trim().trim_end_matches(';');
+    match parse_params(decl).as_slice() {
+        [(_, name)] if !name.is_empty() => Some(name.clone()),
+        _ => None,
+    }
+}
+
+/// Split a C parameter list into the type and name of each parameter.
+fn parse_params(params: &str) -> Vec<(String, String)> {
+    let params = params.trim();
+    if params.is_empty() || params == "void" {
+        return Vec::new();
+    }
+    let name = Regex::new(r"^(.*?)\b([A-Za-z_][A-Za-z0-9_]*)\s*((?:\[[^\]]*\]\s*)*)$").unwrap();
+    split_top_level(params)
+        .into_iter()
+        .map(|p| match name.captures(p.trim()) {
+            // Array extents are part of the type, e.g. `float v[3]` is a `float [3]`
+            Some(c) => (
+                format!("{} {}", c[1].trim(), c[3].trim())
+                    .trim()
+                    .to_string(),
+                c[2].to_string(),
+            ),
+            None => (p.trim().to_string(), String::new()),
+        })
+        .collect()
+}
+
+/// Split on the commas which aren't nested inside parentheses or brackets.
+fn split_top_level(s: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+/// Remove the struct and enum declarations from the header `contents` which were
+/// already declared identically in a header we've seen before. The declarations
+/// seen so far are tracked in `seen` by type name. Returns the de-duplicated
+/// header if any declarations were removed.
+///
+/// Declarations with the same name but a different body are left in place so
+/// the conflict is reported when parsing the header.
+pub fn remove_duplicate_types(
+    contents: &str,
+    seen: &mut HashMap<String, String>,
+) -> Option<String> {
+    let re = Regex::new(
+        r"(?ms)^(?:struct|enum|__ISPC_ALIGNED_STRUCT__\(\d+\))\s+([A-Za-z_][A-Za-z0-9_]*)\s*\{.*?^\};",
+    )
+    .unwrap();
+    let mut deduped = String::new();
+    let mut last = 0;
+    for c in re.captures_iter(contents) {
+        let decl = c.get(0).unwrap();
+        let name = c[1].to_string();
+        match seen.get(&name) {
+            Some(prev) if prev == decl.as_str() => {
+                deduped.push_str(&contents[last..decl.start()]);
+                deduped.push_str(&format!("/* {name} was declared in a previous header */"));
+                last = decl.end();
+            }
+            Some(_) => {}
+            None => {
+                seen.insert(name, decl.as_str().to_string());
+            }
+        }
+    }
+    if last == 0 {
+        None
+    } else {
+        deduped.push_str(&contents[last..]);
+        Some(deduped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remove_repeated_types() {
+        let first = "struct Vec3 {\n    float x;\n};\nextern void foo(struct Vec3 *v);\n";
+        let second = "struct Vec3 {\n    float x;\n};\nstruct Other {\n    int a;\n};\n";
+        let mut seen = HashMap::new();
+        assert_eq!(remove_duplicate_types(first, &mut seen), None);
+        let deduped = remove_duplicate_types(second, &mut seen).unwrap();
+        assert!(!deduped.contains("float x;"), "{deduped}");
+        assert!(deduped.contains("Vec3 was declared in a previous header"));
+        assert!(deduped.contains("struct Other {"));
+    }
+
+    #[test]
+    fn keep_conflicting_types() {
+        let first = "struct Vec3 {\n    float x;\n};\n";
+        let second = "struct Vec3 {\n    double x;\n};\n";
+        let mut seen = HashMap::new();
+        remove_duplicate_types(first, &mut seen);
+        assert_eq!(remove_duplicate_types(second, &mut seen), None);
+    }
+
+    #[test]
+    fn global_declared_name() {
+        assert_eq!(declared_name("float table[256]").as_deref(), Some("table"));
+        assert_eq!(
+            declared_name("const int32_t lut[4][4];").as_deref(),
+            Some("lut")
+        );
+        assert_eq!(declared_name("double scale").as_deref(), Some("scale"));
+        assert_eq!(declared_name(""), None);
+        assert_eq!(declared_name("float a, float b"), None);
+    }
+}
@@ -19,24 +19,76 @@
 //! and Clang link with MSVC on Windows. For bindgen to find libclang you'll need to copy
 //! `libclang.lib` to `clang.lib` and place it in your path.
 //!
+//! # Sharing ISPC Kernels Across a Workspace
+//!
+//! To build the ISPC kernels once and use them from several crates in a workspace,
+//! put them in a dedicated library crate which compiles them in its build script
+//! and re-exports the bindings. Declaring a `links` key lets the build scripts of
+//! dependent crates find the library, see `Config::print_links_metadata`.
+//!
+//! ```toml
+//! # kernels/Cargo.toml
+//! [package]
+//! name = "kernels"
+//! links = "kernels"
+//! ```
+//!
+//! ```ignore
+//! // kernels/src/lib.rs, the bindings are used as kernels::kernels::* elsewhere
+//! ispc_rt::ispc_module!(pub kernels);
+//! ```
+//!
+//! The library is linked into anything depending on the crate, so other crates only
+//! need to depend on `kernels` and `ispc_rt`. A dependent's build script can read the
+//! library's location from `DEP_KERNELS_ROOT`, e.g. to link C++ code against it.
+//!
+//! # Exporting Kernels from a `cdylib`
+//!
+//! When the kernels are linked into a Rust `cdylib`, rustc only exports the
+//! `#[no_mangle]` functions of the Rust code from it, so the functions exported from
+//! ISPC aren't visible to the library's users. To export a kernel, wrap it in a
+//! `#[no_mangle] pub extern "C"` function with a different name, which also decides
+//! exactly which kernels the library exposes.
+//!
+//! ```ignore
+//! ispc_rt::ispc_module!(simple);
+//!
+//! #[no_mangle]
+//! pub extern "C" fn simple_add_lists(a: *const f32, b: *const f32, out: *mut f32, n: i32) {
+//!     unsafe { simple::add_lists(a, b, out, n) }
+//! }
+//! ```
+//!
 
+mod cache;
+mod header;
+mod manifest;
 pub mod opt;
+#[cfg(all(feature = "testing", unix))]
+pub mod testing;
 
 pub use bindgen;
 
-use std::collections::BTreeSet;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::env;
+use std::ffi::OsString;
 use std::fmt::Display;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{BufRead, BufReader, Write};
+use std::mem;
 use std::path::{Path, PathBuf};
-use std::process::{Command, ExitStatus};
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::rc::Rc;
 
 use regex::Regex;
 use semver::{BuildMetadata, Prerelease, Version};
 
+use crate::cache::Cache;
+
 pub use crate::opt::{
-    Addressing, Architecture, MathLib, OptimizationOpt, TargetISA, TargetOS, CPU,
+    Addressing, Architecture, AsmSyntax, ColorChoice, DebugFormat, EmitKind, LibraryKind, LinkKind,
+    MathLib, OptimizationOpt, TargetISA, TargetOS, CPU,
 };
 
 /// Compile the list of ISPC files into a static library and generate bindings
@@ -61,6 +113,105 @@ pub fn compile_library(lib: &str, files: &[&str]) {
     cfg.compile(lib)
 }
 
+/// Compile the list of ISPC files into a static library like `compile_library`,
+/// calling `f` to set any additional options on the `Config` before compiling.
+///
+/// This function will exit the process with `EXIT_FAILURE` if any stage of
+/// compilation or linking fails.
+///
+/// # Example
+/// ```no_run
+/// extern crate ispc_compile;
+/// use ispc_compile::TargetISA;
+///
+/// ispc_compile::compile_library_with("foo", &["src/foo.ispc"], |c| {
+///     c.opt_level(3).target_isa(TargetISA::AVX2i32x8);
+/// });
+/// ```
+pub fn compile_library_with<F: FnOnce(&mut Config)>(lib: &str, files: &[&str], f: F) {
+    let mut cfg = Config::new();
+    for file in files {
+        cfg.file(*file);
+    }
+    f(&mut cfg);
+    cfg.compile(lib)
+}
+
+/// Suffix appended to the names of the debug kernels built by `Config::dual_build`
+pub const DUAL_BUILD_DEBUG_SUFFIX: &str = "_debug";
+
+/// The minimum ISPC version which supports flags that weren't available in all
+/// releases. Flags passed to ISPC are checked against this table before compiling,
+/// to give a clear error instead of ISPC rejecting an unknown flag.
+const FLAG_MIN_VERSIONS: &[(&str, (u64, u64, u64))] = &[
+    ("--instrument", (1, 9, 1)),
+    ("--target-os", (1, 13, 0)),
+    ("--arch=xe64", (1, 16, 0)),
+    ("--opt=reset-ftz-daz", (1, 18, 0)),
+    ("--mcmodel", (1, 16, 0)),
+    ("--vectorcall", (1, 14, 0)),
+    ("--no-vectorcall", (1, 14, 0)),
+];
+
+/// The first ISPC version which no longer supports flags that were removed,
+/// checked like `FLAG_MIN_VERSIONS`.
+const FLAG_MAX_VERSIONS: &[(&str, (u64, u64, u64))] = &[("--emit-c++", (1, 13, 0))];
+
+/// The minimum ISPC version which supports target ISAs that weren't available in
+/// all releases, keyed by the ISA name passed in the `--target` list.
+const ISA_MIN_VERSIONS: &[(&str, (u64, u64, u64))] = &[
+    ("avx512spr", (1, 19, 0)),
+    ("avx2vnni", (1, 21, 0)),
+    ("neon-i32x8", (1, 21, 0)),
+];
+
+/// The architectures ISPC can compile each family of target ISAs for, keyed by the
+/// prefix of the ISA name passed in the `--target` list.
+const ISA_ARCHITECTURES: &[(&str, &[&str])] = &[
+    ("sse", &["x86", "x86_64"]),
+    ("avx", &["x86", "x86_64"]),
+    // The 8 and 16-bit NEON widths are only supported on 32-bit ARM
+    ("neon-i8x16", &["arm"]),
+    ("neon-i16x8", &["arm"]),
+    ("neon", &["arm", "aarch64"]),
+    ("gen9", &["xe64"]),
+    ("xe", &["xe64"]),
+];
+
+/// The target ISAs to pick for the target features Rust is compiling with, in order
+/// of preference. An ISA is picked if all the features listed for it are enabled.
+const RUST_FEATURE_ISAS: &[(TargetISA, &[&str])] = &[
+    (
+        TargetISA::AVX512ICLx16,
+        &[
+            "avx512f",
+            "avx512cd",
+            "avx512vl",
+            "avx512bw",
+            "avx512dq",
+            "avx512vnni",
+            "avx512vbmi2",
+            "avx512bitalg",
+            "avx512vpopcntdq",
+        ],
+    ),
+    (
+        TargetISA::AVX512SKXx16,
+        &["avx512f", "avx512cd", "avx512vl", "avx512bw", "avx512dq"],
+    ),
+    (TargetISA::AVX2VNNIi32x8, &["avx2", "fma", "avxvnni"]),
+    (TargetISA::AVX2i32x8, &["avx2", "fma"]),
+    (TargetISA::AVX1i32x8, &["avx"]),
+    (TargetISA::SSE42i32x4, &["sse4.2"]),
+    (TargetISA::SSE41i32x4, &["sse4.1"]),
+    (TargetISA::SSE2i32x4, &["sse2"]),
+    (TargetISA::Neoni32x4, &["neon"]),
+];
+
+/// The command line length above which the arguments are passed to ISPC in a
+/// response file, to stay under the 8191 character limit of the Windows shell.
+const RESPONSE_FILE_THRESHOLD: usize = 8000;
+
 /// Handy wrapper around calling exit that will log the message passed first
 /// then exit with a failure exit code.
 macro_rules! exit_failure {
@@ -74,91 +225,574 @@ macro_rules! exit_failure {
     }}
 }
 
+/// Check if `program` can be run, either as a path to it or by searching `PATH`
+#[cfg(unix)]
+fn program_exists(program: &Path) -> bool {
+    if program.components().count() > 1 {
+        return program.is_file();
+    }
+    env::var_os("PATH")
+        .map(|paths| env::split_paths(&paths).any(|p| p.join(program).is_file()))
+        .unwrap_or(false)
+}
+
+/// Format a command and its arguments as a single line that can be copied
+/// into a shell to reproduce the invocation.
+fn command_line(cmd: &Command) -> String {
+    let mut line = cmd.get_program().to_string_lossy().into_owned();
+    for arg in cmd.get_args() {
+        let arg = arg.to_string_lossy();
+        if arg.is_empty() || arg.contains(char::is_whitespace) {
+            line.push_str(&format!(" \"{arg}\""));
+        } else {
+            line.push(' ');
+            line.push_str(&arg);
+        }
+    }
+    line
+}
+
+/// Write the arguments of `cmd` to the response file `rsp`, one per line, and
+/// return a command running the same program with `@rsp` as its only argument.
+fn response_file_command(cmd: &Command, rsp: &Path) -> Command {
+    let mut contents = String::new();
+    for arg in cmd.get_args() {
+        let arg = arg.to_string_lossy();
+        if arg.is_empty() || arg.contains(char::is_whitespace) {
+            contents.push_str(&format!("\"{arg}\"\n"));
+        } else {
+            contents.push_str(&arg);
+            contents.push('\n');
+        }
+    }
+    if let Err(e) = fs::write(rsp, contents) {
+        exit_failure!("Failed to write response file {}: {}", rsp.display(), e);
+    }
+    let mut rsp_cmd = Command::new(cmd.get_program());
+    rsp_cmd.arg(format!("@{}", rsp.display()));
+    rsp_cmd
+}
+
+/// Get the version of the ISPC compiler found in the PATH as a
+/// `(major, minor, patch)` tuple. Returns `None` if the compiler couldn't
+/// be run or the version it reported couldn't be parsed.
+pub fn ispc_version() -> Option<(u32, u32, u32)> {
+    let v = parse_ispc_version(&ispc_version_string()?)?;
+    Some((v.major as u32, v.minor as u32, v.patch as u32))
+}
+
+/// Run `ispc --version` and return what it printed
+fn ispc_version_string() -> Option<String> {
+    let cmd_output = Command::new("ispc").arg("--version").output().ok()?;
+    if !cmd_output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&cmd_output.stdout).into_owned())
+}
+
+/// Parse the version number out of the output of `ispc --version`
+fn parse_ispc_version(ver_string: &str) -> Option<Version> {
+    // The ISPC version will be the first version number printed
+    let re = Regex::new(r"(\d+\.\d+\.\d+)").unwrap();
+    let ver = re.captures_iter(ver_string).next()?.get(1)?;
+    Version::parse(ver.as_str()).ok()
+}
+
+/// Make a relative path absolute by resolving it against the current directory
+fn absolute_path(p: &Path) -> PathBuf {
+    if p.is_relative() {
+        env::current_dir().unwrap().join(p)
+    } else {
+        p.to_path_buf()
+    }
+}
+
+/// Resolve the directory `dir` for a link search path, following symlinks and
+/// removing `.` and `..` components so the linker finds the library wherever the
+/// target directory is, e.g. with `CARGO_TARGET_DIR` set to a relative path in a
+/// workspace. Falls back to the absolute path if the directory can't be resolved.
+fn link_search_dir(dir: &Path) -> PathBuf {
+    match fs::canonicalize(dir) {
+        // Not all linkers accept the verbatim `\\?\` paths canonicalize returns on
+        // Windows, so use the plain form for paths on a drive
+        #[cfg(windows)]
+        Ok(p) => match p.to_str().and_then(|s| s.strip_prefix(r"\\?\")) {
+            Some(s) if s.as_bytes().get(1) == Some(&b':') => PathBuf::from(s),
+            _ => p,
+        },
+        #[cfg(not(windows))]
+        Ok(p) => p,
+        Err(_) => absolute_path(dir),
+    }
+}
+
+/// Get the path ISPC writes the object for the target ISA `suffix` to when compiling
+/// for multiple targets, which is named after the `object` path given with `-o`
+fn isa_object_path(object: &Path, suffix: &str) -> PathBuf {
+    let stem = object.file_stem().unwrap().to_string_lossy();
+    let mut isa_object = object.with_file_name(format!("{stem}_{suffix}"));
+    if let Some(ext) = object.extension() {
+        isa_object.set_extension(ext);
+    }
+    isa_object
+}
+
+/// Get the relocation model Rust is compiling with if it was set explicitly,
+/// either through a `relocation_model` cfg or `-C relocation-model` in the RUSTFLAGS.
+fn rustc_relocation_model() -> Option<String> {
+    if let Ok(model) = env::var("CARGO_CFG_RELOCATION_MODEL") {
+        return Some(model);
+    }
+    let flags = env::var("CARGO_ENCODED_RUSTFLAGS").ok()?;
+    let mut model = None;
+    let mut prev = "";
+    for flag in flags.split('\x1f') {
+        let codegen_opt = match flag.strip_prefix("-C") {
+            Some("") => None,
+            Some(opt) => Some(opt),
+            None if prev == "-C" || prev == "--codegen" => Some(flag),
+            None => None,
+        };
+        if let Some(m) = codegen_opt.and_then(|o| o.strip_prefix("relocation-model=")) {
+            // The last relocation model passed is the one used
+            model = Some(m.to_string());
+        }
+        prev = flag;
+    }
+    model
+}
+
+/// Guess the target triple of the host from what the crate was compiled for, used
+/// as the target outside of a build script
+fn host_target() -> String {
+    let arch = match env::consts::ARCH {
+        "x86" => "i686",
+        arch => arch,
+    };
+    match env::consts::OS {
+        "linux" if cfg!(target_env = "musl") => format!("{arch}-unknown-linux-musl"),
+        "linux" => format!("{arch}-unknown-linux-gnu"),
+        "macos" => format!("{arch}-apple-darwin"),
+        "windows" if cfg!(target_env = "gnu") => format!("{arch}-pc-windows-gnu"),
+        "windows" => format!("{arch}-pc-windows-msvc"),
+        os => format!("{arch}-unknown-{os}"),
+    }
+}
+
+/// Get the architecture to pass to ISPC's `--arch` when compiling for the Rust
+/// target triple `target`, or None to let ISPC pick its default
+fn target_arch(target: &str) -> Option<&'static str> {
+    let arch = target.split('-').next().unwrap_or_default();
+    match arch {
+        "x86_64" | "x86_64h" => Some("x86-64"),
+        "i386" | "i486" | "i586" | "i686" => Some("x86"),
+        // The arm64 spellings used by Apple and Windows targets are all 64-bit,
+        // including the ILP32 arm64_32 watchOS targets
+        _ if arch.starts_with("aarch64") || arch.starts_with("arm64") => Some("aarch64"),
+        _ if arch == "arm" || arch.starts_with("armv") || arch.starts_with("thumb") => Some("arm"),
+        _ => None,
+    }
+}
+
+/// Check if a process was terminated by a crash, rather than exiting with
+/// an error code.
+#[cfg(unix)]
+fn crashed(status: &ExitStatus) -> bool {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal().is_some()
+}
+
+/// Check if a process was terminated by a crash, rather than exiting with
+/// an error code. On Windows crashes are reported through NTSTATUS error
+/// codes, e.g. `0xC0000005` for an access violation.
+#[cfg(windows)]
+fn crashed(status: &ExitStatus) -> bool {
+    !matches!(status.code(), Some(c) if (c as u32) < 0xC000_0000)
+}
+
+/// Merge several static libraries, e.g. ISPC libraries built by different crates,
+/// into a single static library named `out_name` in `OUT_DIR`. Returns the path to
+/// the merged library. No Cargo metadata is emitted to link the merged library,
+/// print `cargo:rustc-link-lib=static={out_name}` yourself to link against it.
+///
+/// Object files in different libraries with the same name are prefixed with the
+/// index of the library they came from so they don't overwrite each other.
+///
+/// This function will exit the process with `EXIT_FAILURE` if merging fails.
+///
+/// # Example
+/// ```no_run
+/// extern crate ispc_compile;
+///
+/// ispc_compile::merge_libraries("kernels", &["libfoo.a", "libbar.a"]);
+/// ```
+pub fn merge_libraries<P: AsRef<Path>>(out_name: &str, libs: &[P]) -> PathBuf {
+    let out_dir = match env::var_os("OUT_DIR") {
+        Some(dir) => absolute_path(Path::new(&dir)),
+        None => exit_failure!(
+            "Error: merge_libraries must be called from a build script, OUT_DIR isn't set"
+        ),
+    };
+    let libs: Vec<PathBuf> = libs.iter().map(|l| absolute_path(l.as_ref())).collect();
+    let (merged, status) = merge_archives(out_name, &libs, &out_dir);
+    if !status.success() {
+        exit_failure!("Failed to merge libraries into {}", merged.display());
+    }
+    merged
+}
+
+/// Get a `cc` build configured for `target`, used to find the C compiler and
+/// archiver the same way `cc` does, e.g. respecting `CC`, `AR` and their
+/// target-specific variants when cross compiling
+fn target_cc_build(target: &str) -> cc::Build {
+    let host = env::var("HOST").unwrap_or_else(|_| target.to_string());
+    let mut build = cc::Build::new();
+    build.cargo_metadata(false).target(target).host(&host);
+    build
+}
+
+/// Get the archiver for `build`, exiting with an error if it can't be found
+#[cfg(unix)]
+fn archiver(build: &cc::Build) -> Command {
+    match build.try_get_archiver() {
+        Ok(ar) => ar,
+        Err(e) => exit_failure!("Failed to find the archiver for the target: {}", e),
+    }
+}
+
+/// Extract the objects from each library and archive them together using the
+/// target's archiver
+#[cfg(unix)]
+fn merge_archives(out_name: &str, libs: &[PathBuf], out_dir: &Path) -> (PathBuf, ExitStatus) {
+    let target = env::var("TARGET").unwrap_or_else(|_| host_target());
+    let build = target_cc_build(&target);
+    let merged = out_dir.join(format!("lib{out_name}.a"));
+    let work_dir = out_dir.join(format!("_{out_name}_merge"));
+    let _ = fs::remove_dir_all(&work_dir);
+    let _ = fs::remove_file(&merged);
+    let mut objects = Vec::new();
+    for (i, l) in libs.iter().enumerate() {
+        let lib_dir = work_dir.join(i.to_string());
+        fs::create_dir_all(&lib_dir).unwrap();
+        let status = archiver(&build)
+            .arg("x")
+            .arg(l)
+            .current_dir(&lib_dir)
+            .status();
+        if !status.is_ok_and(|s| s.success()) {
+            exit_failure!("Failed to extract objects from library {}", l.display());
+        }
+        for obj in fs::read_dir(&lib_dir).unwrap() {
+            let obj = obj.unwrap().path();
+            let name = obj.file_name().unwrap().to_string_lossy().into_owned();
+            let prefixed = work_dir.join(format!("{i}_{name}"));
+            fs::rename(&obj, &prefixed).unwrap();
+            objects.push(prefixed);
+        }
+    }
+    let run = |mut cmd: Command| match cmd.status() {
+        Ok(status) => status,
+        Err(e) => exit_failure!("Failed to run {:?}: {}", cmd.get_program(), e),
+    };
+    let apple = target.contains("apple");
+    let mut ar = archiver(&build);
+    ar.arg(if apple { "crs" } else { "crus" })
+        .arg(&merged)
+        .args(&objects);
+    let mut status = run(ar);
+    // Index Mach-O archives with ranlib, see `Config::assemble`
+    if apple && status.success() {
+        let mut ranlib = match build.try_get_ranlib() {
+            Ok(ranlib) => ranlib,
+            Err(e) => exit_failure!("Failed to find ranlib for the target: {}", e),
+        };
+        ranlib.arg(&merged);
+        status = run(ranlib);
+    }
+    (merged, status)
+}
+
+/// Merge the libraries using `lib.exe`, which can take libraries as inputs directly.
+/// The tool is found like `cc` finds the archiver, so `AR` can be set to its path
+/// if it isn't found through the Visual Studio installation.
+#[cfg(windows)]
+fn merge_archives(out_name: &str, libs: &[PathBuf], out_dir: &Path) -> (PathBuf, ExitStatus) {
+    let merged = out_dir.join(format!("{out_name}.lib"));
+    let target = env::var("TARGET").unwrap_or_else(|_| host_target());
+    let mut lib_cmd = match target_cc_build(&target).try_get_archiver() {
+        Ok(tool) => tool,
+        Err(e) => exit_failure!(
+            "Failed to find lib.exe for the MSVC toolchain ({}), set its path with AR",
+            e
+        ),
+    };
+    lib_cmd.arg(format!("/OUT:{}", merged.display())).args(libs);
+    match lib_cmd.status() {
+        Ok(status) => (merged, status),
+        Err(e) => exit_failure!("Failed to run {:?}: {}", lib_cmd.get_program(), e),
+    }
+}
+
+/// The files produced by compiling an ISPC library, passed to the
+/// callback set with `Config::after_compile`.
+pub struct CompileOutput {
+    /// The library the ISPC code was linked into
+    pub library: PathBuf,
+    /// The object files compiled by ISPC which were linked into the library
+    pub objects: Vec<PathBuf>,
+    /// The C headers generated by ISPC for each source file
+    pub headers: Vec<PathBuf>,
+    /// The additional outputs written for inspecting the generated code, requested
+    /// with `Config::emit`
+    pub emitted: Vec<PathBuf>,
+    /// The target ISAs each exported function was compiled for in a build targeting
+    /// multiple ISAs, keyed by function name. The ISAs are named as in ISPC's
+    /// per-target symbols and objects, e.g. `avx2`. Empty when compiling for a
+    /// single target.
+    pub target_variants: BTreeMap<String, Vec<String>>,
+    /// The file the generated Rust bindings were written to, None if bindings
+    /// weren't generated or bindgen failed and `Config::allow_bindgen_failure` was set
+    pub bindings: Option<PathBuf>,
+}
+
+/// An ISPC invocation compiling one source file, and the files it produces
+struct IspcJob<'a> {
+    src: &'a Path,
+    ispc_fname: String,
+    cmd: Command,
+    cmd_line: String,
+    object: PathBuf,
+    header: PathBuf,
+    deps: PathBuf,
+    isa_objects: Vec<PathBuf>,
+    /// Hash of the arguments, recorded in the cache once compiled
+    args_hash: u64,
+    /// Whether the outputs are up to date with the inputs recorded in the cache
+    up_to_date: bool,
+}
+
+/// Renames functions in the generated bindings, bindgen keeps linking them by the
+/// name ISPC exported them with
+#[derive(Debug)]
+struct RenameFunctions(BTreeMap<String, String>);
+
+impl bindgen::callbacks::ParseCallbacks for RenameFunctions {
+    fn generated_name_override(&self, item: bindgen::callbacks::ItemInfo<'_>) -> Option<String> {
+        match item.kind {
+            bindgen::callbacks::ItemKind::Function => self.0.get(item.name).cloned(),
+            _ => None,
+        }
+    }
+}
+
+/// Callback run with the compiled files after a successful compile
+type AfterCompileFn = dyn Fn(&CompileOutput);
+
+/// Callback run before each ISPC invocation with its index, the total and the source
+type ProgressFn = dyn Fn(usize, usize, &Path);
+
 /// Extra configuration to be passed to ISPC
+///
+/// A `Config` can be cloned to use shared settings as the base for several
+/// libraries. Each `compile` starts from scratch, so nothing from compiling
+/// one library is carried over to the others.
+///
+/// Some settings can also be overridden from the environment, e.g. on CI,
+/// without editing the build script:
+///
+/// - `ISPC_FLAGS`: extra whitespace separated flags passed to ISPC before the
+///   ones set by the `Config`, so the `Config`'s settings win where they overlap.
+/// - `ISPC_TARGET`: the target ISA list passed to `--target`, e.g. `avx2-i32x8`.
+/// - `ISPC_OPT_LEVEL`: the optimization level.
+///
+/// Settings made explicitly on the `Config` take precedence over the environment,
+/// which takes precedence over the defaults derived from Cargo's settings.
+#[derive(Clone)]
 pub struct Config {
     ispc_version: Version,
+    ispc_version_string: String,
+    print_compiler_version: bool,
     ispc_files: Vec<PathBuf>,
+    generated_sources: Vec<(String, String)>,
     include_paths: Vec<PathBuf>,
     // These options are set from the environment if not set by the user
     out_dir: Option<PathBuf>,
+    build_dir: Option<PathBuf>,
     debug: Option<bool>,
+    debug_format: Option<DebugFormat>,
     opt_level: Option<u32>,
     target: Option<String>,
     cargo_metadata: bool,
+    print_search_paths: bool,
+    print_link_libs: bool,
+    print_rerun_if_changed: bool,
+    print_links_metadata: bool,
     // Additional ISPC compiler options that the user can set
     defines: Vec<(String, Option<String>)>,
     math_lib: MathLib,
+    svml_path: Option<PathBuf>,
+    link_libs: Vec<(String, LinkKind)>,
+    msvc_lib_tool: Option<PathBuf>,
     addressing: Option<Addressing>,
     optimization_opts: BTreeSet<OptimizationOpt>,
     cpu_target: Option<CPU>,
     force_alignment: Option<u32>,
+    pic: Option<bool>,
+    library_kind: LibraryKind,
+    code_model: Option<String>,
+    vectorcall: Option<bool>,
     no_omit_frame_ptr: bool,
     no_stdlib: bool,
     no_cpp: bool,
+    dual_build: bool,
+    strip: bool,
     quiet: bool,
     werror: bool,
     woff: bool,
+    color: ColorChoice,
     wno_perf: bool,
+    warn_unoptimized_release: bool,
     instrument: bool,
     enable_llvm_intrinsics: bool,
     target_isa: Option<Vec<TargetISA>>,
     architecture: Option<Architecture>,
     target_os: Option<TargetOS>,
     bindgen_builder: bindgen::Builder,
+    custom_bindgen_builder: bool,
+    clang_args: Vec<String>,
+    layout_tests: Option<bool>,
+    bindings_name: Option<String>,
+    function_renames: BTreeMap<String, String>,
+    globals: Vec<(String, String)>,
+    c_header: Option<PathBuf>,
+    keep_intermediates: Option<bool>,
+    linker_script: Option<PathBuf>,
+    export_list: Option<Vec<String>>,
+    manifest: bool,
+    cache: bool,
+    dry_run: bool,
+    jobs: Option<usize>,
+    object_paths: HashMap<PathBuf, PathBuf>,
+    verbose: bool,
+    emit_cpp: bool,
+    emit_kinds: Vec<EmitKind>,
+    asm_syntax: Option<AsmSyntax>,
+    cpp_files: Vec<PathBuf>,
+    bindgen_headers: Vec<PathBuf>,
+    bindgen_only: Option<Vec<PathBuf>>,
+    generate_bindings: bool,
+    bindings_writer: Option<Rc<RefCell<dyn Write>>>,
+    allow_bindgen_failure: bool,
+    after_compile: Option<Rc<AfterCompileFn>>,
+    progress: Option<Rc<ProgressFn>>,
+    // The number of ISPC invocations started by the current compile
+    jobs_started: Cell<usize>,
+    // The intermediate files written by the current compile
+    intermediates: RefCell<Vec<PathBuf>>,
+    // The results of the last compile
+    objects: RefCell<Vec<PathBuf>>,
+    headers: RefCell<Vec<PathBuf>>,
+    isa_objects: RefCell<BTreeMap<String, Vec<PathBuf>>>,
+    bitcode: RefCell<Vec<PathBuf>>,
+    bindgen_error: RefCell<Option<String>>,
 }
 
 impl Config {
     pub fn new() -> Config {
         // Query the ISPC compiler version. This also acts as a check that we can
         // find the ISPC compiler when we need it later.
-        let cmd_output = Command::new("ispc")
-            .arg("--version")
-            .output()
-            .expect("Failed to find ISPC compiler in PATH");
-        if !cmd_output.status.success() {
-            exit_failure!("Failed to get ISPC version, is it in your PATH?");
-        }
-        let ver_string = String::from_utf8_lossy(&cmd_output.stdout);
-        // The ISPC version will be the first version number printed
-        let re = Regex::new(r"(\d+\.\d+\.\d+)").unwrap();
-        let ispc_ver = Version::parse(
-            re.captures_iter(&ver_string)
-                .next()
-                .expect("Failed to parse ISPC version")
-                .get(1)
-                .expect("Failed to parse ISPC version")
-                .as_str(),
-        )
-        .expect("Failed to parse ISPC version");
+        let ver_string = match ispc_version_string() {
+            Some(v) => v,
+            None => exit_failure!("Failed to get ISPC version, is it in your PATH?"),
+        };
+        Config::with_version_string(&ver_string)
+    }
+    /// Create a `Config` for the ISPC compiler which reported the version `ver_string`
+    fn with_version_string(ver_string: &str) -> Config {
+        let ispc_ver = parse_ispc_version(ver_string).expect("Failed to parse ISPC version");
 
         Config {
             ispc_version: ispc_ver,
+            ispc_version_string: ver_string.trim().to_string(),
+            print_compiler_version: false,
             ispc_files: Vec::new(),
+            generated_sources: Vec::new(),
             include_paths: Vec::new(),
             out_dir: None,
+            build_dir: None,
             debug: None,
+            debug_format: None,
             opt_level: None,
             target: None,
             cargo_metadata: true,
+            print_search_paths: true,
+            print_link_libs: true,
+            print_rerun_if_changed: true,
+            print_links_metadata: true,
             defines: Vec::new(),
             math_lib: MathLib::ISPCDefault,
+            svml_path: None,
+            link_libs: Vec::new(),
+            msvc_lib_tool: None,
             addressing: None,
             optimization_opts: BTreeSet::new(),
             cpu_target: None,
             force_alignment: None,
+            pic: None,
+            library_kind: LibraryKind::Static,
+            code_model: None,
+            vectorcall: None,
             no_omit_frame_ptr: false,
             no_stdlib: false,
             no_cpp: false,
+            dual_build: false,
+            strip: false,
             quiet: false,
             werror: false,
             woff: false,
+            color: ColorChoice::Auto,
             wno_perf: false,
+            warn_unoptimized_release: true,
             instrument: false,
             enable_llvm_intrinsics: false,
             target_isa: None,
             architecture: None,
             target_os: None,
             bindgen_builder: Default::default(),
+            custom_bindgen_builder: false,
+            clang_args: Vec::new(),
+            layout_tests: None,
+            bindings_name: None,
+            function_renames: BTreeMap::new(),
+            globals: Vec::new(),
+            c_header: None,
+            keep_intermediates: None,
+            linker_script: None,
+            export_list: None,
+            manifest: false,
+            cache: true,
+            dry_run: false,
+            jobs: None,
+            object_paths: HashMap::new(),
+            verbose: false,
+            emit_cpp: false,
+            emit_kinds: Vec::new(),
+            asm_syntax: None,
+            cpp_files: Vec::new(),
+            bindgen_headers: Vec::new(),
+            bindgen_only: None,
+            generate_bindings: true,
+            allow_bindgen_failure: false,
+            bindings_writer: None,
+            after_compile: None,
+            progress: None,
+            jobs_started: Cell::new(0),
+            intermediates: RefCell::new(Vec::new()),
+            objects: RefCell::new(Vec::new()),
+            headers: RefCell::new(Vec::new()),
+            isa_objects: RefCell::new(BTreeMap::new()),
+            bitcode: RefCell::new(Vec::new()),
+            bindgen_error: RefCell::new(None),
         }
     }
     /// Add an ISPC file to be compiled
@@ -166,18 +800,57 @@ impl Config {
         self.ispc_files.push(file.as_ref().to_path_buf());
         self
     }
-    /// Set the output directory to override the default of `env!("OUT_DIR")`
+    /// Add ISPC source generated by the build script to be compiled. The source
+    /// is written to `{name}.ispc` in `OUT_DIR` and compiled as if it had been
+    /// added with `file`, so the library's exports are named after `name`.
+    ///
+    /// No `rerun-if-changed` directive is emitted for the generated file, it's
+    /// up to the build script to emit directives for the inputs it's generated from.
+    /// The file is written by `compile`, and isn't written in a dry run.
+    pub fn source_string(&mut self, name: &str, contents: &str) -> &mut Config {
+        self.generated_sources
+            .push((name.to_string(), contents.to_string()));
+        self
+    }
+    /// Set the output directory to override the default of `env!("OUT_DIR")`. This
+    /// must be set to use the `Config` outside of a build script.
     pub fn out_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Config {
         self.out_dir = Some(dir.as_ref().to_path_buf());
         self
     }
     /// Set whether debug symbols should be generated, symbols are generated by
-    /// default if `env!("DEBUG") == "true"`
+    /// default if `env!("DEBUG") == "true"`, or not at all if it isn't set
     pub fn debug(&mut self, debug: bool) -> &mut Config {
         self.debug = Some(debug);
         self
     }
-    /// Set the optimization level to override the default of `env!("OPT_LEVEL")`
+    /// Set the format of the debug information generated when debug symbols are
+    /// enabled, by default ISPC uses the platform's usual format. DWARF formats
+    /// only apply to non-Windows targets and CodeView only to Windows targets.
+    pub fn debug_format(&mut self, format: DebugFormat) -> &mut Config {
+        self.debug_format = Some(format);
+        self
+    }
+    /// Set the version of the DWARF debug information generated when debug symbols
+    /// are enabled, e.g. to pin an older version for an older debugger. This is the
+    /// same as setting the matching `DebugFormat` with `debug_format`, and only applies
+    /// when `debug` is enabled. ISPC supports DWARF versions 2 to 5, other versions
+    /// will exit the process with an error.
+    pub fn dwarf_version(&mut self, version: u32) -> &mut Config {
+        let format = match version {
+            2 => DebugFormat::Dwarf2,
+            3 => DebugFormat::Dwarf3,
+            4 => DebugFormat::Dwarf4,
+            5 => DebugFormat::Dwarf5,
+            _ => exit_failure!(
+                "Error: ISPC only supports DWARF versions 2 to 5, not {}",
+                version
+            ),
+        };
+        self.debug_format(format)
+    }
+    /// Set the optimization level to override the default of `env!("OPT_LEVEL")`,
+    /// or 0 if it isn't set
     pub fn opt_level(&mut self, opt_level: u32) -> &mut Config {
         self.opt_level = Some(opt_level);
         self
@@ -194,6 +867,23 @@ impl Config {
             .push((define.to_string(), value.map(|s| s.to_string())));
         self
     }
+    /// Add a define named `define` whose value is read from the environment variable
+    /// `env_var` when the build script runs, e.g. to pass a version or a Cargo feature
+    /// (`CARGO_FEATURE_*`) to the ISPC code. The build script is rerun when the variable
+    /// changes. Exits with an error if the variable isn't set.
+    pub fn define_from_env(&mut self, define: &str, env_var: &str) -> &mut Config {
+        let value = match env::var(env_var) {
+            Ok(v) => v,
+            Err(e) => exit_failure!(
+                "Failed to read environment variable {} for ISPC define {}: {}",
+                env_var,
+                define,
+                e
+            ),
+        };
+        self.print(&format!("cargo:rerun-if-env-changed={env_var}"));
+        self.add_define(define, Some(&value))
+    }
     /// Select the 32 or 64 bit addressing calculations for addressing calculations in ISPC.
     pub fn addressing(&mut self, addressing: Addressing) -> &mut Config {
         self.addressing = Some(addressing);
@@ -204,11 +894,196 @@ impl Config {
         self.math_lib = math_lib;
         self
     }
+    /// Set the directory containing the Intel SVML runtime libraries to link against
+    /// when using `MathLib::SVML`. If not set the libraries must be found on the
+    /// linker's default search path.
+    pub fn svml_path<P: AsRef<Path>>(&mut self, path: P) -> &mut Config {
+        self.svml_path = Some(path.as_ref().to_path_buf());
+        self
+    }
+    /// Link an additional library needed by the ISPC code, e.g. one providing
+    /// `extern "C"` functions called from ISPC. The library is linked after the
+    /// ISPC library when Cargo metadata is emitted.
+    pub fn link_lib(&mut self, name: &str, kind: LinkKind) -> &mut Config {
+        self.link_libs.push((name.to_string(), kind));
+        self
+    }
+    /// Set the path to the `lib.exe` (or compatible, e.g. `llvm-lib.exe`) tool used to
+    /// create the static library on Windows. By default the tool is found through
+    /// the Visual Studio installation, which may fail for non-standard installs.
+    /// Shared libraries are linked with the linker in the same directory, `link.exe`
+    /// or `lld-link.exe` for `llvm-lib.exe`.
+    pub fn msvc_lib_tool<P: AsRef<Path>>(&mut self, tool: P) -> &mut Config {
+        self.msvc_lib_tool = Some(tool.as_ref().to_path_buf());
+        self
+    }
+    /// Set whether Rust bindings to the library should be generated (the default).
+    /// If you write your own declarations of the exported functions or use another
+    /// binding generator, disabling this skips running bindgen so libclang isn't
+    /// required to build. The library is still linked as usual.
+    pub fn generate_bindings(&mut self, generate: bool) -> &mut Config {
+        self.generate_bindings = generate;
+        self
+    }
+    /// Write the generated Rust bindings to `w` instead of the bindings file in the
+    /// output directory, e.g. for tools which want the binding text without a file
+    /// on disk. No file is written, so `CompileOutput::bindings` is None and the
+    /// bindings are regenerated on every compile. Configs cloned from this one share
+    /// the writer.
+    pub fn generate_bindings_to<W: Write + 'static>(&mut self, w: W) -> &mut Config {
+        self.bindings_writer = Some(Rc::new(RefCell::new(w)));
+        self
+    }
+    /// Continue building if bindgen fails to generate bindings to the library, e.g.
+    /// because the ISPC code exports types bindgen can't handle yet. The library is
+    /// still compiled and linked, but no bindings are written and you must provide
+    /// your own declarations of the exported functions. The error is printed as a
+    /// Cargo warning and can be read back with `bindgen_error`.
+    pub fn allow_bindgen_failure(&mut self, allow: bool) -> &mut Config {
+        self.allow_bindgen_failure = allow;
+        self
+    }
+    /// Set whether to skip recompiling ISPC sources which haven't changed since the
+    /// last build (on by default). A source is recompiled if its contents, the
+    /// contents of any file it includes, or the arguments it's compiled with change.
+    /// The hashes of the inputs are stored in `.ispc-cache.json` in `OUT_DIR`.
+    ///
+    /// This also skips regenerating the Rust bindings when the headers they're
+    /// generated from, the bindgen options, libclang and the build script haven't
+    /// changed, so editing only the implementation of a kernel doesn't rerun bindgen.
+    /// The bindings are always regenerated when a `bindgen_builder` is set or other
+    /// headers are bound with `bindgen_header` or `bindgen_only`, since the settings
+    /// and callbacks on the builder and the files the headers include can't be tracked.
+    pub fn cache(&mut self, cache: bool) -> &mut Config {
+        self.cache = cache;
+        self
+    }
+    /// Write a JSON manifest listing each function exported from the library
+    /// with its parameters and types, as declared in the ISPC generated headers.
+    /// The manifest is written to `{lib}.ispc.json` in the output directory.
+    pub fn manifest(&mut self, manifest: bool) -> &mut Config {
+        self.manifest = manifest;
+        self
+    }
+    /// Also write a single header declaring everything the library exports to `path`,
+    /// for C or C++ code using the same kernels. The headers ISPC generates for each
+    /// source are combined into it with the repeated type declarations removed, along
+    /// with the globals added with `export_global`, so it doesn't depend on any files
+    /// in the build directory.
+    pub fn c_header<P: AsRef<Path>>(&mut self, path: P) -> &mut Config {
+        self.c_header = Some(path.as_ref().to_path_buf());
+        self
+    }
+    /// Link the shared library with the linker script `script`, passed to the linker
+    /// with `-T`, e.g. to control where the kernels' sections are placed. This is only
+    /// supported for `LibraryKind::Shared` on targets linked with a GNU compatible
+    /// linker, since static libraries aren't linked and the Apple and MSVC linkers
+    /// don't take linker scripts.
+    pub fn linker_script<P: AsRef<Path>>(&mut self, script: P) -> &mut Config {
+        self.linker_script = Some(script.as_ref().to_path_buf());
+        self
+    }
+    /// Set whether to keep the intermediate files which are only needed while
+    /// compiling, to inspect what was passed to ISPC and bindgen. Defaults to keeping
+    /// them in debug builds and removing them after a successful release build.
+    ///
+    /// The build directory (`OUT_DIR` unless set otherwise) contains:
+    ///
+    /// - `{name}_ispc.o`, `{name}_ispc.h` and `{name}_ispc.idep`: the object, header
+    ///   and dependency list ISPC writes for each source, which are always kept so
+    ///   unchanged sources aren't recompiled.
+    /// - `{name}_ispc.rsp`: the response file arguments are passed in when the command
+    ///   line is too long. Intermediate.
+    /// - `_{lib}_ispc_bindgen_header.h`: the header including all the ISPC headers
+    ///   which is passed to bindgen. Intermediate.
+    /// - `{name}_ispc.dedup.h`: copies of the ISPC headers with the types already
+    ///   declared by another header removed, included by the bindgen header.
+    ///   Intermediate.
+    pub fn keep_intermediates(&mut self, keep: bool) -> &mut Config {
+        self.keep_intermediates = Some(keep);
+        self
+    }
+    /// Enable or disable fast, lower precision math. This is a shortcut for setting
+    /// `OptimizationOpt::FastMath` (`--opt=fast-math`), which allows non-IEEE-compliant
+    /// optimizations of numeric expressions, and `MathLib::Fast` (`--math-lib=fast`),
+    /// which uses faster, lower accuracy versions of the math functions. Disabling it
+    /// removes both settings if they were set.
+    pub fn fast_math(&mut self, fast: bool) -> &mut Config {
+        if fast {
+            self.optimization_opts.insert(OptimizationOpt::FastMath);
+            self.math_lib = MathLib::Fast;
+        } else {
+            self.optimization_opts.remove(&OptimizationOpt::FastMath);
+            if let MathLib::Fast = self.math_lib {
+                self.math_lib = MathLib::ISPCDefault;
+            }
+        }
+        self
+    }
     /// Set an optimization option.
     pub fn optimization_opt(&mut self, opt: OptimizationOpt) -> &mut Config {
         self.optimization_opts.insert(opt);
         self
     }
+    /// Set whether fused multiply-add instructions are disabled, which gives results
+    /// that are reproducible across targets with and without FMA support.
+    pub fn disable_fma(&mut self, disable: bool) -> &mut Config {
+        self.set_optimization_opt(OptimizationOpt::DisableFMA, disable)
+    }
+    /// Set whether ISPC should always issue aligned vector loads and stores, which
+    /// requires the data passed to the kernels to be aligned to the vector width.
+    pub fn force_aligned_memory(&mut self, force: bool) -> &mut Config {
+        self.set_optimization_opt(OptimizationOpt::ForceAlignedMemory, force)
+    }
+    /// Set whether loop unrolling is disabled.
+    pub fn disable_loop_unroll(&mut self, disable: bool) -> &mut Config {
+        self.set_optimization_opt(OptimizationOpt::DisableLoopUnroll, disable)
+    }
+    /// Set whether ISPC is prevented from generating gather instructions, loading
+    /// each lane separately instead. This is for debugging, e.g. to isolate a
+    /// miscompile or measure what the gathers cost, and hurts performance.
+    pub fn disable_gathers(&mut self, disable: bool) -> &mut Config {
+        self.set_optimization_opt(OptimizationOpt::DisableGathers, disable)
+    }
+    /// Set whether ISPC is prevented from generating scatter instructions, storing
+    /// each lane separately instead. Like `disable_gathers` this is for debugging and
+    /// hurts performance.
+    pub fn disable_scatters(&mut self, disable: bool) -> &mut Config {
+        self.set_optimization_opt(OptimizationOpt::DisableScatters, disable)
+    }
+    /// Set whether masked vector loads may load whole vectors, which is faster but
+    /// can read past the end of an array, so the memory after the data must be safe
+    /// to read. This only changes the code generated for SSE targets, on AVX and
+    /// later ISAs with native masked loads it has no effect.
+    pub fn fast_masked_vload(&mut self, fast: bool) -> &mut Config {
+        self.set_optimization_opt(OptimizationOpt::FastMaskedVload, fast)
+    }
+    /// Add or remove the optimization option `opt`
+    fn set_optimization_opt(&mut self, opt: OptimizationOpt, enabled: bool) -> &mut Config {
+        if enabled {
+            self.optimization_opts.insert(opt);
+        } else {
+            self.optimization_opts.remove(&opt);
+        }
+        self
+    }
+    /// Set whether to warn when the ISPC code is compiled with `-O0` in a release
+    /// build, which is usually a misconfiguration. Setting the optimization level
+    /// explicitly with `opt_level` also silences the warning. Defaults to true.
+    pub fn warn_unoptimized_release(&mut self, warn: bool) -> &mut Config {
+        self.warn_unoptimized_release = warn;
+        self
+    }
+    /// Strip the debug info from the library after it's linked, e.g. to ship a lean
+    /// library from a build which enabled `debug` for profiling. The exported symbols
+    /// are kept. The strip tool is found next to the target's archiver, so `AR` and
+    /// its target-specific variants also pick the strip tool when cross compiling.
+    /// If it isn't installed, or on Windows, the library is left as is with a
+    /// warning. Defaults to false.
+    pub fn strip(&mut self, strip: bool) -> &mut Config {
+        self.strip = strip;
+        self
+    }
     /// Set the cpu target. This overrides the default choice of ISPC which
     /// is to target the host CPU.
     pub fn cpu(&mut self, cpu: CPU) -> &mut Config {
@@ -220,11 +1095,59 @@ impl Config {
         self.force_alignment = Some(alignment);
         self
     }
-    /// Add an extra include path for the ispc compiler to search for files.
+    /// Add an extra include path for the ispc compiler to search for files. `OUT_DIR`
+    /// is always searched after the paths added here, so headers generated by the
+    /// build script can be included. Both must be valid UTF-8, the build exits with
+    /// an error otherwise.
     pub fn include_path<P: AsRef<Path>>(&mut self, path: P) -> &mut Config {
         self.include_paths.push(path.as_ref().to_path_buf());
         self
     }
+    /// Set whether position independent code should be generated. By default PIC
+    /// is generated to match the relocation model Rust is compiling with, which is
+    /// PIC on all targets except Windows and bare-metal (`-none`) targets unless
+    /// `-C relocation-model` is set.
+    ///
+    /// The `crt-static` target feature doesn't change the default. Rust links static
+    /// executables on Linux as static PIE, which needs position independent code. Set
+    /// `pic(false)` or `-C relocation-model=static` for a non-PIE static executable.
+    ///
+    /// Linking code which isn't position independent into a PIE executable or a
+    /// shared library fails with relocation errors suggesting to "recompile with
+    /// -fPIC", so `compile` warns if `pic(false)` is set while Rust is generating
+    /// position independent code. The setting used is printed with `verbose`.
+    pub fn pic(&mut self, pic: bool) -> &mut Config {
+        self.pic = Some(pic);
+        self
+    }
+    /// Set whether ISPC should use the `__vectorcall` calling convention, which passes
+    /// vector arguments in registers, on x86_64 Windows targets. The exported functions
+    /// are declared `extern "vectorcall"` in the generated bindings to match, which
+    /// currently requires a nightly compiler with `#![feature(abi_vectorcall)]`.
+    /// This setting is ignored on other targets.
+    pub fn vectorcall(&mut self, vectorcall: bool) -> &mut Config {
+        self.vectorcall = Some(vectorcall);
+        self
+    }
+    /// Set the kind of library to link the ISPC code into, by default a static
+    /// library is built. Shared libraries are always compiled with `--pic`.
+    pub fn library_kind(&mut self, kind: LibraryKind) -> &mut Config {
+        self.library_kind = kind;
+        self
+    }
+    /// Set the code model ISPC should generate code for, either `small` or `large`.
+    /// This is passed to ISPC as `--mcmodel`, and can be combined with `pic(false)`
+    /// to generate position dependent code for embedded targets.
+    pub fn code_model(&mut self, model: &str) -> &mut Config {
+        if model != "small" && model != "large" {
+            exit_failure!(
+                "Error: unsupported ISPC code model '{}', expected 'small' or 'large'",
+                model
+            );
+        }
+        self.code_model = Some(model.to_string());
+        self
+    }
     /// Disable frame pointer omission. It may be useful for profiling to
     /// disable omission.
     pub fn no_omit_frame_pointer(&mut self) -> &mut Config {
@@ -236,11 +1159,28 @@ impl Config {
         self.no_stdlib = true;
         self
     }
-    /// Don't run the C preprocessor
+    /// Don't run the C preprocessor. Note that defines are handled by the preprocessor,
+    /// so any defines added with `add_define` will be ignored.
     pub fn no_cpp(&mut self) -> &mut Config {
         self.no_cpp = true;
         self
     }
+    /// Set whether the C preprocessor should be skipped when compiling, like `no_cpp`
+    /// but which can also turn it back on.
+    pub fn disable_preprocessor(&mut self, disable: bool) -> &mut Config {
+        self.no_cpp = disable;
+        self
+    }
+    /// Build each kernel twice, once unoptimized with debug symbols (`-O0 -g`)
+    /// and once optimized (`-O3`), and link both into the library. The debug
+    /// variant of each exported function is named with the `_debug` suffix
+    /// (see `DUAL_BUILD_DEBUG_SUFFIX`), so `foo` and `foo_debug` will both be
+    /// available in the generated bindings. This overrides the `debug` and
+    /// `opt_level` settings and requires the preprocessor.
+    pub fn dual_build(&mut self, dual: bool) -> &mut Config {
+        self.dual_build = dual;
+        self
+    }
     /// Enable suppression of all ispc compiler output.
     pub fn quiet(&mut self) -> &mut Config {
         self.quiet = true;
@@ -256,11 +1196,24 @@ impl Config {
         self.woff = true;
         self
     }
+    /// Set whether ISPC should color its diagnostics, e.g. to keep escape codes
+    /// out of CI logs. The default is `ColorChoice::Auto`.
+    pub fn color(&mut self, color: ColorChoice) -> &mut Config {
+        self.color = color;
+        self
+    }
     /// Don't issue warnings related to performance issues
     pub fn wno_perf(&mut self) -> &mut Config {
         self.wno_perf = true;
         self
     }
+    /// Set whether warnings related to performance issues, like the gathers and
+    /// scatters ISPC had to generate, should be suppressed (`--wno-perf`). Other
+    /// warnings are still reported.
+    pub fn suppress_perf_warnings(&mut self, suppress: bool) -> &mut Config {
+        self.wno_perf = suppress;
+        self
+    }
     /// Emit instrumentation code for ISPC to gather performance data such
     /// as vector utilization.
     pub fn instrument(&mut self) -> &mut Config {
@@ -280,11 +1233,19 @@ impl Config {
         self.instrument = true;
         self
     }
-    /// Enable support for LLVM intrinsics
+    /// Enable support for LLVM intrinsics, letting ISPC code call them directly,
+    /// e.g. `@llvm.sqrt(x)`, which ISPC rejects unless `--enable-llvm-intrinsics`
+    /// is passed.
     pub fn enable_llvm_intrinsics(&mut self) -> &mut Config {
         self.enable_llvm_intrinsics = true;
         self
     }
+    /// Set whether ISPC code may call LLVM intrinsics directly, like
+    /// `enable_llvm_intrinsics` but which can also turn it back off. Defaults to false.
+    pub fn llvm_intrinsics(&mut self, enable: bool) -> &mut Config {
+        self.enable_llvm_intrinsics = enable;
+        self
+    }
     /// Select the target ISA and vector width. If none is specified ispc will
     /// choose the host CPU ISA and vector width.
     pub fn target_isa(&mut self, target: TargetISA) -> &mut Config {
@@ -299,6 +1260,31 @@ impl Config {
         self.target_isa = Some(targets);
         self
     }
+    /// Select the target ISA matching the target features Rust is compiling with,
+    /// e.g. `avx2-i32x8` when AVX2 and FMA are enabled through `-C target-cpu=native`
+    /// or `-C target-feature`, so the ISPC and Rust code assume the same CPU. ISAs
+    /// the installed ISPC doesn't support are skipped. The target ISA is left
+    /// unchanged if no ISA matches the features, or outside of a build script.
+    pub fn target_from_rust_cfg(&mut self) -> &mut Config {
+        let features = match env::var("CARGO_CFG_TARGET_FEATURE") {
+            Ok(f) => f,
+            Err(_) => return self,
+        };
+        let features: Vec<&str> = features.split(',').collect();
+        let supported = |isa: &TargetISA| {
+            let name = isa.to_string();
+            ISA_MIN_VERSIONS.iter().all(|&(prefix, min)| {
+                !name.starts_with(prefix) || self.ispc_version >= Version::new(min.0, min.1, min.2)
+            })
+        };
+        let isa = RUST_FEATURE_ISAS.iter().find(|(isa, required)| {
+            required.iter().all(|f| features.contains(f)) && supported(isa)
+        });
+        if let Some(&(isa, _)) = isa {
+            self.target_isa(isa);
+        }
+        self
+    }
     /// Select the CPU architecture to target
     pub fn target_arch(&mut self, arch: Architecture) -> &mut Config {
         self.architecture = Some(arch);
@@ -309,141 +1295,1411 @@ impl Config {
         self.target_os = Some(os);
         self
     }
-    /// Set whether Cargo metadata should be emitted to link to the compiled library
+    /// Set whether Cargo metadata should be emitted to link to the compiled library.
+    /// This sets all the kinds of metadata, which can then be toggled individually
+    /// with `print_search_paths`, `print_link_libs`, `print_rerun_if_changed` and
+    /// `print_links_metadata`.
     pub fn cargo_metadata(&mut self, metadata: bool) -> &mut Config {
         self.cargo_metadata = metadata;
+        self.print_search_paths = metadata;
+        self.print_link_libs = metadata;
+        self.print_rerun_if_changed = metadata;
+        self.print_links_metadata = metadata;
+        self
+    }
+    /// Set whether the `cargo:rustc-link-search` directives for the library and the
+    /// libraries it depends on are emitted, e.g. to add the search paths yourself.
+    pub fn print_search_paths(&mut self, print: bool) -> &mut Config {
+        self.print_search_paths = print;
+        self
+    }
+    /// Set whether the `cargo:rustc-link-lib` directives linking the library and the
+    /// libraries it depends on are emitted, e.g. to link them with your own directives.
+    pub fn print_link_libs(&mut self, print: bool) -> &mut Config {
+        self.print_link_libs = print;
         self
     }
+    /// Set whether the `cargo:rerun-if-changed` and `cargo:rerun-if-env-changed`
+    /// directives for the ISPC sources, their includes and the environment variables
+    /// read are emitted. Without them Cargo reruns the build script whenever any
+    /// file in the package changes.
+    pub fn print_rerun_if_changed(&mut self, print: bool) -> &mut Config {
+        self.print_rerun_if_changed = print;
+        self
+    }
+    /// Set whether metadata describing the compiled library is passed on to the build
+    /// scripts of dependent packages, when the package declares a `links` key. For
+    /// `links = "kernels"` a dependent's build script can read:
+    ///
+    /// - `DEP_KERNELS_ROOT`: the directory the library was written to
+    /// - `DEP_KERNELS_LIB`: the name of the library file to link, e.g. `kernels`
+    /// - `DEP_KERNELS_BINDINGS`: the path to the generated Rust bindings
+    /// - `DEP_KERNELS_INCLUDE`: the directory of the C header written by `c_header`
+    ///
+    /// The bindings and include paths are only passed on if they were written.
+    /// Nothing is printed for packages without a `links` key. Defaults to true.
+    pub fn print_links_metadata(&mut self, print: bool) -> &mut Config {
+        self.print_links_metadata = print;
+        self
+    }
+    /// Set the bindgen builder used to generate the bindings, to customize how the
+    /// headers are translated. The generated ISPC headers are added to the builder
+    /// when compiling. For example, if bindgen can't translate a type exported from
+    /// your ISPC code it can be emitted as an opaque blob instead:
+    ///
+    /// ```no_run
+    /// ispc_compile::Config::new()
+    ///     .file("src/simple.ispc")
+    ///     .bindgen_builder(ispc_compile::bindgen::builder().opaque_type("Unusual"))
+    ///     .compile("simple");
+    /// ```
     pub fn bindgen_builder(&mut self, builder: bindgen::Builder) -> &mut Self {
         self.bindgen_builder = builder;
+        self.custom_bindgen_builder = true;
+        self
+    }
+    /// Set whether the generated bindings should include bindgen's layout tests,
+    /// `#[test]` functions checking that the size, alignment and field offsets of
+    /// each struct match the ISPC headers, to catch layout mismatches at `cargo test`
+    /// time. This overrides the setting on the `bindgen_builder`, if it isn't called
+    /// the builder's setting is used, which is on by default.
+    pub fn layout_tests(&mut self, layout_tests: bool) -> &mut Config {
+        self.layout_tests = Some(layout_tests);
+        self
+    }
+    /// Add an argument to pass to libclang when parsing the ISPC headers to generate
+    /// the bindings, e.g. `--sysroot` when cross-compiling. The target triple is
+    /// passed automatically to parse the headers for the target being compiled for,
+    /// unless a `--target` or `-target` argument is given here.
+    pub fn clang_arg(&mut self, arg: &str) -> &mut Config {
+        self.clang_args.push(arg.to_string());
+        self
+    }
+    /// Only generate bindings for the exported functions in `functions`, e.g. to bind
+    /// the public API of a kernel library but not internal helpers which need to be
+    /// exported for other reasons. The types used by these functions are still bound.
+    /// By default bindings are generated for every exported function.
+    pub fn export_list<S: AsRef<str>>(&mut self, functions: &[S]) -> &mut Config {
+        self.export_list = Some(functions.iter().map(|f| f.as_ref().to_string()).collect());
+        self
+    }
+    /// Set the file name (without the `.rs` extension) to write the generated bindings
+    /// to, overriding the default of naming the file after the library. Bindings written
+    /// to a different file can be imported with `ispc_module!(lib, "name")`.
+    pub fn bindings_name(&mut self, name: &str) -> &mut Config {
+        self.bindings_name = Some(name.to_string());
+        self
+    }
+    /// Name the binding to the exported ISPC function `from` as `to` on the Rust side,
+    /// e.g. to avoid a clash when two libraries whose bindings are imported into the
+    /// same module export a function with the same name. The function is still linked
+    /// by the name ISPC exported it with, so the ISPC code doesn't need to change.
+    pub fn rename_function(&mut self, from: &str, to: &str) -> &mut Config {
+        self.function_renames
+            .insert(from.to_string(), to.to_string());
+        self
+    }
+    /// Generate a binding to a global variable defined in the ISPC code, which isn't
+    /// declared in the headers ISPC generates. The variable is given as its C
+    /// declaration, e.g. `float table[256]` for `uniform float table[256];`, and is
+    /// bound as an `extern` static linked from the library. With `dual_build` the
+    /// debug variant of the kernels uses its own copy of the variable, named with
+    /// the `_debug` suffix, which isn't bound.
+    pub fn export_global(&mut self, declaration: &str) -> &mut Config {
+        let declaration = declaration.trim().trim_end_matches(';').to_string();
+        match header::declared_name(&declaration) {
+            Some(name) => self.globals.push((name, declaration)),
+            None => exit_failure!(
+                "Error: export_global expects a C declaration like `float table[256]`, got `{}`",
+                declaration
+            ),
+        }
+        self
+    }
+    /// Set a callback to be run after the library has been successfully compiled
+    /// and the bindings generated. The callback is passed the paths to the files
+    /// produced, e.g. to copy them elsewhere or run additional code generation.
+    pub fn after_compile<F: Fn(&CompileOutput) + 'static>(&mut self, f: F) -> &mut Config {
+        self.after_compile = Some(Rc::new(f));
+        self
+    }
+    /// Set a callback to be run before each ISPC invocation starts, e.g. to report the
+    /// progress of builds compiling many kernels. It's passed the index of the
+    /// invocation, the total number of invocations and the source file compiled.
+    /// Each source is compiled once, plus once per kind of output set with `emit` and
+    /// once more for `dual_build`. Sources which are up to date are counted as well.
+    pub fn progress<F: Fn(usize, usize, &Path) + 'static>(&mut self, f: F) -> &mut Config {
+        self.progress = Some(Rc::new(f));
+        self
+    }
+    /// Only generate Rust bindings to the types declared in `headers`, without
+    /// compiling or linking any ISPC code. This lets Rust and ISPC share struct
+    /// layouts declared once in a header included by the ISPC sources. The headers
+    /// are parsed as C with the `uniform` and `varying` qualifiers removed and
+    /// ISPC's fixed width integer types (e.g. `int32`) mapped to the C ones, so they
+    /// can't use other ISPC-only syntax. The bindings are written and imported with
+    /// `ispc_module!` as usual, and any ISPC files added to the `Config` are ignored.
+    pub fn bindgen_only<P: AsRef<Path>>(&mut self, headers: &[P]) -> &mut Config {
+        self.bindgen_only = Some(headers.iter().map(|h| h.as_ref().to_path_buf()).collect());
+        self
+    }
+    /// Also write the given kinds of output, e.g. assembly, for each source file, to
+    /// inspect the generated code without a separate build. They're written next
+    /// to the objects, named after the source file and the kind of output, e.g.
+    /// `foo_ispc_s.s`, and are compiled with the same options as the objects.
+    /// Replaces any kinds set previously.
+    pub fn emit(&mut self, kinds: &[EmitKind]) -> &mut Config {
+        self.emit_kinds = kinds.to_vec();
+        self
+    }
+    /// Also write the LLVM bitcode for each source file, which can be found with
+    /// `bitcode` after compiling. This is a shortcut for adding `EmitKind::Llvm` to the
+    /// kinds of output set with `emit`. The library is still compiled and linked from
+    /// the objects as usual.
+    pub fn emit_bitcode(&mut self, emit: bool) -> &mut Config {
+        self.emit_kinds.retain(|k| *k != EmitKind::Llvm);
+        if emit {
+            self.emit_kinds.push(EmitKind::Llvm);
+        }
+        self
+    }
+    /// Set the syntax of the x86 assembly written when `EmitKind::Asm` is passed to
+    /// `emit`. Defaults to ISPC's choice, which is AT&T syntax.
+    pub fn asm_syntax(&mut self, syntax: AsmSyntax) -> &mut Config {
+        self.asm_syntax = Some(syntax);
+        self
+    }
+    /// Add a C or C++ source file to compile with the C++ compiler and link into the
+    /// library along with the ISPC code, e.g. a thin shim around the kernels. The
+    /// headers ISPC generates for the kernels are on its include path, along with
+    /// the paths added with `include_path`. Use `bindgen_header` to generate
+    /// bindings to the functions the glue code exports.
+    pub fn cpp_file<P: AsRef<Path>>(&mut self, p: P) -> &mut Config {
+        self.cpp_files.push(p.as_ref().to_path_buf());
+        self
+    }
+    /// Add a header to generate Rust bindings to along with the ISPC headers, e.g. the
+    /// header declaring the functions exported from a `cpp_file`. The header is parsed
+    /// as C, so C++ declarations must be wrapped in `extern "C"` blocks guarded by
+    /// `#ifdef __cplusplus`.
+    pub fn bindgen_header<P: AsRef<Path>>(&mut self, p: P) -> &mut Config {
+        self.bindgen_headers.push(p.as_ref().to_path_buf());
+        self
+    }
+    /// Write the object compiled from the ISPC source file `source` to `dest` instead
+    /// of the build directory, e.g. to consume it from another build system. `source`
+    /// must be the same path given to `file`. The object is still linked into the
+    /// library as usual. When compiling for multiple targets, the per-target objects
+    /// are written next to `dest`.
+    pub fn object_path<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        source: P,
+        dest: Q,
+    ) -> &mut Config {
+        self.object_paths
+            .insert(source.as_ref().to_path_buf(), dest.as_ref().to_path_buf());
+        self
+    }
+    /// Set whether `compile` should print the settings it resolved, e.g. the target,
+    /// optimization level, defines and the arguments passed to ISPC, as Cargo warnings
+    /// to help track down why ISPC was run with the flags it was. Defaults to false.
+    pub fn verbose(&mut self, verbose: bool) -> &mut Config {
+        self.verbose = verbose;
+        self
+    }
+    /// Set whether ISPC should emit C++ for its generic target instead of
+    /// compiling to native code, which is then compiled with the C++ compiler and
+    /// linked into the library. This is a fallback for architectures ISPC has no
+    /// code generator for. The emitted code includes `generic-16.h`, which implements
+    /// the target's vector intrinsics (e.g. the one in ISPC's `examples/intrinsics`),
+    /// and must be found in one of the include paths. The generic targets were
+    /// removed in ISPC 1.13, so this requires an older ISPC. Defaults to false.
+    pub fn emit_cpp(&mut self, emit: bool) -> &mut Config {
+        self.emit_cpp = emit;
+        self
+    }
+    /// Set the maximum number of ISPC processes to run at once when compiling
+    /// multiple source files. This defaults to Cargo's `NUM_JOBS`, so the ISPC
+    /// build shares the parallelism Cargo was given with the rest of the build
+    /// instead of oversubscribing the machine, or the number of CPUs outside of
+    /// a build script.
+    pub fn jobs(&mut self, jobs: usize) -> &mut Config {
+        self.jobs = Some(jobs.max(1));
+        self
+    }
+    /// Set whether `compile` should only print the ISPC and archiver commands it
+    /// would run and the files it would produce, without running them or writing
+    /// any files. Nothing is linked, so this isn't meant to be left on in a build
+    /// script. Defaults to false.
+    pub fn dry_run(&mut self, dry_run: bool) -> &mut Config {
+        self.dry_run = dry_run;
         self
     }
     /// The library name should not have any prefix or suffix, e.g. instead of
     /// `libexample.a` or `example.lib` simply pass `example`
     pub fn compile(&self, lib: &str) {
         let dst = self.get_out_dir();
-        let build_dir = self.get_build_dir();
-        let default_args = self.default_args();
-        let mut objects = vec![];
-        let mut headers = vec![];
-        for s in &self.ispc_files {
-            let fname = s
-                .file_stem()
-                .expect("ISPC source files must be files")
-                .to_str()
-                .expect("ISPC source file names must be valid UTF-8");
-            self.print(&format!("cargo:rerun-if-changed={}", s.display()));
-
-            let ispc_fname = String::from(fname) + "_ispc";
-            let object = build_dir.join(ispc_fname.clone()).with_extension("o");
-            let header = build_dir.join(ispc_fname.clone()).with_extension("h");
-            let deps = build_dir.join(ispc_fname.clone()).with_extension("idep");
-            let output = Command::new("ispc")
-                .args(&default_args)
-                .arg(s)
-                .arg("-o")
-                .arg(&object)
-                .arg("-h")
-                .arg(&header)
-                .arg("-MMM")
-                .arg(&deps)
-                .output()
-                .unwrap();
-
-            if !output.stderr.is_empty() {
-                let stderr = String::from_utf8_lossy(&output.stderr);
-                for l in stderr.lines() {
-                    self.print(&format!("cargo:warning=(ISPC) {l}"));
-                }
+        self.objects.borrow_mut().clear();
+        self.headers.borrow_mut().clear();
+        self.jobs_started.set(0);
+        self.intermediates.borrow_mut().clear();
+        self.isa_objects.borrow_mut().clear();
+        self.bitcode.borrow_mut().clear();
+        self.bindgen_error.borrow_mut().take();
+        if let Some(ref headers) = self.bindgen_only {
+            let headers: Vec<PathBuf> = headers.iter().map(|h| absolute_path(h)).collect();
+            for h in &headers {
+                self.print(&format!("cargo:rerun-if-changed={}", h.display()));
             }
-            if !output.status.success() {
-                exit_failure!("Failed to compile ISPC source file {}", s.display());
-            }
-            objects.push(object);
-            headers.push(header);
-
-            // Go this files dependencies and add them to Cargo's watch list
-            let deps_list = File::open(deps)
-                .unwrap_or_else(|_| panic!("Failed to open dependencies list for {}", s.display()));
-            let reader = BufReader::new(deps_list);
-            for d in reader.lines() {
-                // Don't depend on the ISPC "stdlib" file which is output as a dependency
-                let dep_name = d.unwrap();
-                self.print(&format!("cargo:rerun-if-changed={dep_name}"));
-            }
-
-            // Push on the additional ISA-specific object files if any were generated
-            if let Some(ref t) = self.target_isa {
-                if t.len() > 1 {
-                    for isa in t.iter() {
-                        let isa_fname = ispc_fname.clone() + "_" + &isa.lib_suffix();
-                        let isa_obj = build_dir.join(isa_fname).with_extension("o");
-                        objects.push(isa_obj);
-                    }
-                }
+            if self.dry_run {
+                println!(
+                    "ispc-rs: would generate {}",
+                    self.bindings_path(lib).display()
+                );
+                return;
             }
+            self.write_bindings(lib, &headers);
+            self.print(&format!("cargo:rustc-env=ISPC_OUT_DIR={}", dst.display()));
+            self.remove_intermediates();
+            return;
         }
-        let libfile = lib.to_owned() + &self.get_target();
-        if !self.assemble(&libfile, &objects).success() {
-            exit_failure!("Failed to assemble ISPC objects into library {lib}");
+        for var in &["ISPC_FLAGS", "ISPC_TARGET", "ISPC_OPT_LEVEL"] {
+            self.print(&format!("cargo:rerun-if-env-changed={var}"));
         }
-        self.print(&format!("cargo:rustc-link-lib=static={libfile}"));
-
-        // Now generate a header we can give to bindgen and generate bindings
-        let bindgen_header = self.generate_bindgen_header(lib, &headers);
-        let bindings = self
+        if self.print_compiler_version {
+            self.print(&format!(
+                "cargo:warning=ispc-rs: Using {}",
+                self.ispc_version_string
+            ));
+        }
+        let mut objects = vec![];
+        let mut headers = vec![];
+        let mut exports = vec![];
+        let mut cache = self
+            .cache
+            .then(|| Cache::load(&self.get_build_dir().join(".ispc-cache.json")));
+        if self.dual_build && self.no_cpp {
+            exit_failure!(
+                "Error: dual_build renames the debug kernels through the preprocessor \
+                 and can't be used with no_cpp"
+            );
+        }
+        if self.vectorcall.is_some() && self.get_vectorcall().is_none() {
+            self.print(
+                &"cargo:warning=ispc-rs: vectorcall is only supported on x86_64 Windows \
+                  targets and will be ignored",
+            );
+        }
+        if let Some(ref f) = self.debug_format {
+            let windows = self.get_target().contains("windows");
+            if (*f == DebugFormat::CodeView) != windows {
+                self.print(
+                    &"cargo:warning=ispc-rs: The debug format isn't supported on this target, \
+                      ISPC will use the platform's default format",
+                );
+            }
+        }
+        if self.asm_syntax.is_some() && !self.emit_kinds.contains(&EmitKind::Asm) {
+            self.print(
+                &"cargo:warning=ispc-rs: asm_syntax only applies to assembly written with \
+                  emit(&[EmitKind::Asm]) and will be ignored",
+            );
+        }
+        if let Some(ref script) = self.linker_script {
+            let target = self.get_target();
+            if let LibraryKind::Static = self.library_kind {
+                exit_failure!(
+                    "Error: A linker script can only be used with LibraryKind::Shared, \
+                     static libraries aren't linked"
+                );
+            }
+            if target.contains("apple") || target.contains("msvc") {
+                exit_failure!(
+                    "Error: The linker for {} doesn't support linker scripts",
+                    target
+                );
+            }
+            self.print(&format!("cargo:rerun-if-changed={}", script.display()));
+        }
+        match (&self.library_kind, self.pic) {
+            (LibraryKind::Shared, Some(false)) => self.print(
+                &"cargo:warning=ispc-rs: pic(false) is ignored when building a shared library, \
+                  which requires position independent code",
+            ),
+            // The link of the Rust crate fails much later with errors like "relocation
+            // R_X86_64_32 against `.rodata' can not be used when making a PIE object;
+            // recompile with -fPIC", which don't point back to this setting
+            (LibraryKind::Static, Some(false)) if self.rust_pic() => self.print(
+                &"cargo:warning=ispc-rs: pic(false) disables position independent code while \
+                  Rust is compiling position independent code, linking the library into a PIE \
+                  executable or a shared library will fail with relocation errors (\"recompile \
+                  with -fPIC\"). Remove pic(false) or build with -C relocation-model=static",
+            ),
+            _ => {}
+        }
+        // An unoptimized release build usually means the ISPC code was accidentally left
+        // unoptimized, e.g. by an override in the environment
+        if self.warn_unoptimized_release
+            && self.opt_level.is_none()
+            && !self.dual_build
+            && self.get_opt_level() == 0
+            && env::var("PROFILE").as_deref() == Ok("release")
+        {
+            self.print(
+                &"cargo:warning=ispc-rs: ISPC compiled with -O0 in a release build, \
+                  set opt_level(0) to silence this warning if it's intended",
+            );
+        }
+        // In a dual build the primary kernels are always the optimized variant
+        let default_args = if self.dual_build {
+            self.ispc_args(false, 3)
+        } else {
+            self.default_args()
+        };
+        self.check_flag_support(&default_args);
+        self.check_isa_architecture(&default_args);
+        if self.verbose {
+            self.print_settings(&default_args);
+        }
+        if !self.dry_run {
+            self.write_generated_sources();
+        }
+        let mut jobs = Vec::new();
+        let mut ispc_fnames = Vec::new();
+        let mut primary_objects = Vec::new();
+        let ispc_files = self.source_files();
+        for s in &ispc_files {
+            // The name is only used to name the outputs, so a file name which isn't
+            // valid UTF-8 is converted lossily instead of being rejected
+            let fname = s
+                .file_stem()
+                .expect("ISPC source files must be files")
+                .to_string_lossy();
+            // Sources generated with source_string are tracked by the build script's own inputs
+            if self.ispc_files.contains(s) {
+                self.print(&format!("cargo:rerun-if-changed={}", s.display()));
+            }
+            let ispc_fname = fname.into_owned() + "_ispc";
+            let object = self.object_paths.get(s).map(|p| absolute_path(p));
+            if let Some(dir) = object.as_ref().and_then(|o| o.parent()) {
+                if !self.dry_run {
+                    if let Err(e) = fs::create_dir_all(dir) {
+                        exit_failure!("Failed to create directory {}: {}", dir.display(), e);
+                    }
+                }
+            }
+            let job = self.ispc_job(
+                s,
+                &default_args,
+                ispc_fname.clone(),
+                None,
+                object,
+                cache.as_ref(),
+            );
+            primary_objects.push(job.object.clone());
+            jobs.push(job);
+            ispc_fnames.push(ispc_fname);
+        }
+        let primary_headers = self.run_jobs(jobs, &mut objects, cache.as_mut());
+        if self.manifest && !self.dry_run {
+            for (s, h) in ispc_files.iter().zip(&primary_headers) {
+                exports.push((s.as_path(), header::exported_signatures(h)));
+            }
+        }
+
+        if let Some(isas) = self.get_target_isa().filter(|isas| isas.len() > 1) {
+            for isa in isas {
+                let suffix = isa.lib_suffix();
+                let objects = primary_objects
+                    .iter()
+                    .map(|o| isa_object_path(o, &suffix))
+                    .collect();
+                self.isa_objects.borrow_mut().insert(suffix, objects);
+            }
+        }
+        let target_variants = if self.dry_run {
+            BTreeMap::new()
+        } else {
+            self.target_variants(&primary_objects, &primary_headers)
+        };
+
+        // Write any additional outputs requested for inspecting the generated code. ISPC
+        // writes a single kind of output per run, so each kind is a separate run
+        let mut emitted = Vec::new();
+        if !self.emit_kinds.is_empty() {
+            let mut jobs = Vec::new();
+            for (s, ispc_fname) in ispc_files.iter().zip(&ispc_fnames) {
+                for kind in &self.emit_kinds {
+                    let mut args = default_args.clone();
+                    args.push(kind.to_string());
+                    if let (EmitKind::Asm, Some(syntax)) = (kind, self.asm_syntax) {
+                        args.push(syntax.to_string());
+                    }
+                    let fname = format!("{ispc_fname}_{}", kind.extension());
+                    jobs.push(self.ispc_job(s, &args, fname, Some(*kind), None, cache.as_ref()));
+                }
+            }
+            self.run_jobs(jobs, &mut emitted, cache.as_mut());
+            *self.bitcode.borrow_mut() = emitted
+                .iter()
+                .filter(|p| {
+                    p.extension()
+                        .is_some_and(|e| e == EmitKind::Llvm.extension())
+                })
+                .cloned()
+                .collect();
+        }
+
+        if self.dual_build {
+            // Build the debug variant of the kernels, renaming each exported
+            // function with the preprocessor so both variants can live in the library
+            let mut jobs = Vec::new();
+            for ((s, header), ispc_fname) in
+                ispc_files.iter().zip(&primary_headers).zip(ispc_fnames)
+            {
+                let mut debug_args = self.ispc_args(true, 0);
+                // A dry run doesn't generate the header, so the renames are only shown
+                // if it's left over from a previous build
+                let exported = if header.exists() {
+                    header::exported_functions(header)
+                } else {
+                    Vec::new()
+                };
+                for f in exported {
+                    debug_args.push(format!("-D{f}={f}{DUAL_BUILD_DEBUG_SUFFIX}"));
+                }
+                // Each variant defines the globals, so rename them to avoid duplicate symbols
+                for (g, _) in &self.globals {
+                    debug_args.push(format!("-D{g}={g}{DUAL_BUILD_DEBUG_SUFFIX}"));
+                }
+                let debug_fname = ispc_fname + DUAL_BUILD_DEBUG_SUFFIX;
+                jobs.push(self.ispc_job(s, &debug_args, debug_fname, None, None, cache.as_ref()));
+            }
+            let debug_headers = self.run_jobs(jobs, &mut objects, cache.as_mut());
+            for (h, d) in primary_headers.into_iter().zip(debug_headers) {
+                headers.push(h);
+                headers.push(d);
+            }
+        } else {
+            headers = primary_headers;
+        }
+        if self.emit_cpp {
+            objects = self.compile_cpp(&objects);
+        }
+        for f in self.cpp_files.iter().chain(&self.bindgen_headers) {
+            self.print(&format!("cargo:rerun-if-changed={}", f.display()));
+        }
+        if !self.cpp_files.is_empty() {
+            objects.extend(self.compile_cpp(&self.cpp_files));
+        }
+        *self.objects.borrow_mut() = objects.clone();
+        *self.headers.borrow_mut() = headers.clone();
+        if objects.is_empty() {
+            exit_failure!(
+                "Error: No objects to link into {}, add ISPC files to compile with Config::file",
+                lib
+            );
+        }
+        let libfile = lib.to_owned() + &self.get_target();
+        let link_cmds = self.link_commands(&libfile, &objects);
+        if self.dry_run {
+            for cmd in &link_cmds {
+                println!("ispc-rs: would run: {}", command_line(cmd));
+            }
+            println!(
+                "ispc-rs: would produce {}",
+                self.library_path(&libfile).display()
+            );
+            if self.generate_bindings {
+                println!(
+                    "ispc-rs: would generate {}",
+                    self.bindings_path(lib).display()
+                );
+            }
+            return;
+        }
+        if let Some(ref c) = cache {
+            if let Err(e) = c.save() {
+                self.print(&format!(
+                    "cargo:warning=ispc-rs: Failed to write the ISPC build cache: {e}"
+                ));
+            }
+        }
+        // ar adds to an existing archive instead of replacing it, so remove the library
+        // from a previous compile to avoid keeping objects which are no longer built
+        let _ = fs::remove_file(self.library_path(&libfile));
+        let failure = match self.library_kind {
+            LibraryKind::Static => "Failed to assemble ISPC objects into library",
+            LibraryKind::Shared => "Failed to link ISPC objects into shared library",
+        };
+        let linked = link_cmds.into_iter().all(|mut cmd| match cmd.status() {
+            Ok(status) => status.success(),
+            Err(e) => exit_failure!(
+                "{} {}, failed to run {}: {}",
+                failure,
+                lib,
+                cmd.get_program().to_string_lossy(),
+                e
+            ),
+        });
+        match self.library_kind {
+            LibraryKind::Static => {
+                if !linked {
+                    exit_failure!("{failure} {lib}");
+                }
+                // An archive without any members is just its 8 byte signature, which
+                // would otherwise only fail later with unresolved symbols
+                let archive = self.library_path(&libfile);
+                if fs::metadata(&archive).map_or(0, |m| m.len()) <= 8 {
+                    exit_failure!(
+                        "Error: The archiver produced an empty library {}",
+                        archive.display()
+                    );
+                }
+                self.print(&format!("cargo:rustc-link-lib=static={libfile}"));
+            }
+            LibraryKind::Shared => {
+                if !linked {
+                    exit_failure!("{failure} {lib}");
+                }
+                self.print(&format!("cargo:rustc-link-lib=dylib={libfile}"));
+            }
+        }
+        if self.cpp_files.is_empty()
+            && self.globals.is_empty()
+            && headers
+                .iter()
+                .all(|h| header::exported_functions(h).is_empty())
+        {
+            self.print(&format!(
+                "cargo:warning=ispc-rs: None of the ISPC files export any functions, so {lib} \
+                 has nothing to call. Mark the functions to call from Rust with `export`"
+            ));
+        }
+        if let MathLib::SVML = self.math_lib {
+            self.link_svml();
+        }
+        // Libraries the ISPC code depends on must come after it for static linking
+        for (name, kind) in &self.link_libs {
+            self.print(&format!("cargo:rustc-link-lib={kind}{name}"));
+        }
+
+        let bindings = if self.generate_bindings {
+            let mut bindgen_headers = headers.clone();
+            bindgen_headers.extend(self.bindgen_headers.iter().map(|h| absolute_path(h)));
+            self.write_bindings(lib, &bindgen_headers)
+        } else {
+            None
+        };
+
+        if let Some(ref path) = self.c_header {
+            self.write_c_header(lib, &headers, &absolute_path(path));
+        }
+
+        if self.manifest {
+            let manifest_file = dst.join(format!("{lib}.ispc.json"));
+            if let Err(e) = fs::write(&manifest_file, manifest::to_json(lib, &exports)) {
+                exit_failure!(
+                    "Failed to write manifest {}: {}",
+                    manifest_file.display(),
+                    e
+                );
+            }
+        }
+
+        self.print(&format!(
+            "cargo:rustc-link-search=native={}",
+            link_search_dir(&dst).display()
+        ));
+        self.print(&format!("cargo:rustc-env=ISPC_OUT_DIR={}", dst.display()));
+        self.write_links_metadata(&libfile, bindings.as_deref());
+
+        self.remove_intermediates();
+
+        if let Some(ref f) = self.after_compile {
+            f(&CompileOutput {
+                library: self.library_path(&libfile),
+                objects,
+                headers,
+                emitted,
+                target_variants,
+                bindings,
+            });
+        }
+    }
+    /// Compile the library like `compile` and return the source of the Rust bindings
+    /// generated for it instead of writing them to a file, e.g. to snapshot test the
+    /// bindings. The bindings are generated even if `generate_bindings` is disabled.
+    /// Returns None if bindgen failed and failures are allowed, other errors exit
+    /// like in `compile`.
+    pub fn generate_bindings_string(&mut self, lib: &str) -> Option<String> {
+        let buffer = Rc::new(RefCell::new(Vec::new()));
+        let writer = self.bindings_writer.replace(buffer.clone());
+        let generate = mem::replace(&mut self.generate_bindings, true);
+        self.compile(lib);
+        self.bindings_writer = writer;
+        self.generate_bindings = generate;
+        let bindings = buffer.take();
+        if bindings.is_empty() {
+            return None;
+        }
+        match String::from_utf8(bindings) {
+            Ok(b) => Some(b),
+            Err(e) => exit_failure!("Rust bindings to {} aren't valid UTF-8: {}", lib, e),
+        }
+    }
+    /// Generate the Rust bindings to the functions declared in the ISPC `headers`
+    /// and write them to the bindings file for `lib`, or the writer set with
+    /// `generate_bindings_to`. Returns the path to the bindings file, or None if
+    /// they were written to the writer or bindgen failed and failures are allowed.
+    fn write_bindings(&self, lib: &str, headers: &[PathBuf]) -> Option<PathBuf> {
+        // Generate a header we can give to bindgen and generate bindings
+        let bindgen_header = self.generate_bindgen_header(lib, headers);
+        // bindgen only takes the header path as a string
+        let Some(bindgen_header) = bindgen_header.to_str() else {
+            exit_failure!(
+                "The bindgen header path {} must be valid UTF-8, set an out_dir which is",
+                bindgen_header.display()
+            );
+        };
+        let mut bindings = self
             .bindgen_builder
             .clone()
-            .header(bindgen_header.to_str().unwrap());
+            .header(bindgen_header)
+            .clang_args(self.bindgen_clang_args());
+        if let Some(layout_tests) = self.layout_tests {
+            bindings = bindings.layout_tests(layout_tests);
+        }
+        // Only bind the functions in the export list if there is one, bindgen will still
+        // bind the types they use
+        if let Some(ref list) = self.export_list {
+            let exported: Vec<String> = headers
+                .iter()
+                .flat_map(|h| header::exported_functions(h))
+                .collect();
+            for f in list {
+                if !exported.contains(f) {
+                    self.print(&format!(
+                        "cargo:warning=ispc-rs: {f} is in the export list but isn't exported from {lib}"
+                    ));
+                }
+                bindings = bindings.allowlist_function(f);
+                if self.dual_build {
+                    bindings = bindings.allowlist_function(format!("{f}{DUAL_BUILD_DEBUG_SUFFIX}"));
+                }
+            }
+            for (g, _) in &self.globals {
+                bindings = bindings.allowlist_var(g);
+            }
+        }
+        // The exported functions must be called with the same convention ISPC compiled them with
+        if self.get_vectorcall() == Some(true) {
+            for h in headers {
+                for f in header::exported_functions(h) {
+                    bindings = bindings.override_abi(bindgen::Abi::Vectorcall, f);
+                }
+            }
+        }
+        if !self.function_renames.is_empty() {
+            let mut renames = self.function_renames.clone();
+            if self.dual_build {
+                for (from, to) in &self.function_renames {
+                    renames.insert(
+                        format!("{from}{DUAL_BUILD_DEBUG_SUFFIX}"),
+                        format!("{to}{DUAL_BUILD_DEBUG_SUFFIX}"),
+                    );
+                }
+            }
+            bindings = bindings.parse_callbacks(Box::new(RenameFunctions(renames)));
+        }
+
+        let bindgen_file = self.bindings_path(lib);
+
+        // bindgen panics if it can't load libclang, so check for it first to give a helpful error
+        if !clang_sys::is_loaded() {
+            if let Err(e) = clang_sys::load() {
+                let msg = format!(
+                    "Failed to find libclang, which is needed to generate Rust bindings to {lib} \
+                     ({e}). Install libclang or set LIBCLANG_PATH to the directory containing it. \
+                     On Windows you'll also need to copy libclang.lib to clang.lib. If you don't \
+                     need the bindings, disable them with Config::generate_bindings(false)."
+                );
+                if self.allow_bindgen_failure {
+                    self.print(&format!("cargo:warning=ispc-rs: {msg}"));
+                    *self.bindgen_error.borrow_mut() = Some(msg);
+                    return None;
+                }
+                exit_failure!("Error: {}", msg);
+            }
+        }
 
-        let bindgen_file = dst.join(lib).with_extension("rs");
+        // Running bindgen is slow, so skip it if neither the headers nor the bindgen
+        // options changed since the bindings were last written, e.g. when only the
+        // implementation of a kernel was edited. Only the ISPC headers are tracked, the
+        // headers a user passes may include others, and a custom builder's callbacks
+        // and settings don't all show up in its flags
+        let cacheable = self.cache
+            && !self.custom_bindgen_builder
+            && self.bindgen_headers.is_empty()
+            && self.bindgen_only.is_none();
+        let mut bindgen_cache =
+            cacheable.then(|| Cache::load(&self.get_build_dir().join(".ispc-bindgen-cache.json")));
+        let entry = bindgen_cache.as_ref().and_then(|_| {
+            let mut inputs = headers.to_vec();
+            inputs.push(PathBuf::from(bindgen_header));
+            // The build script is rebuilt when it or its dependencies change, so hashing
+            // it covers an update to bindgen, which can change the bindings for the same flags
+            if let Ok(exe) = env::current_exe() {
+                inputs.push(exe);
+            }
+            // The renames are done by a callback, which isn't included in the flags
+            let mut args = bindings.command_line_flags();
+            args.extend(
+                self.function_renames
+                    .iter()
+                    .map(|(from, to)| format!("{from}={to}")),
+            );
+            args.push(bindgen::clang_version().full);
+            cache::hash_files(&inputs).map(|inputs| cache::Entry {
+                inputs,
+                args: cache::hash_args(&args),
+            })
+        });
+        if let (Some(c), Some(e), None) = (&bindgen_cache, &entry, &self.bindings_writer) {
+            if bindgen_file.exists() && c.get(lib) == Some(e) {
+                return Some(bindgen_file);
+            }
+        }
 
-        let generated_bindings = match bindings.generate() {
-            Ok(b) => b.to_string(),
-            Err(_) => exit_failure!("Failed to generating Rust bindings to {}", lib),
+        match bindings.generate() {
+            Ok(b) => {
+                let module = format!(
+                    "#[allow(non_camel_case_types,dead_code,non_upper_case_globals,non_snake_case,improper_ctypes)]\n\
+                     pub mod {lib} {{\n{b}}}"
+                );
+                if let Some(ref w) = self.bindings_writer {
+                    if let Err(e) = w.borrow_mut().write_all(module.as_bytes()) {
+                        exit_failure!("Failed to write Rust bindings to {}: {}", lib, e);
+                    }
+                    return None;
+                }
+                if let Err(e) = fs::write(&bindgen_file, module) {
+                    exit_failure!("Failed to write bindgen mod file: {}", e);
+                }
+                if let (Some(c), Some(e)) = (&mut bindgen_cache, entry) {
+                    c.insert(lib, e);
+                    if let Err(e) = c.save() {
+                        self.print(&format!(
+                            "cargo:warning=ispc-rs: Failed to write the bindgen cache: {e}"
+                        ));
+                    }
+                }
+                Some(bindgen_file)
+            }
+            // The library was still built, so the user can link it with their own bindings
+            Err(e) if self.allow_bindgen_failure => {
+                self.print(&format!(
+                    "cargo:warning=ispc-rs: Failed to generate Rust bindings to {lib}, \
+                     they must be provided manually: {e}"
+                ));
+                *self.bindgen_error.borrow_mut() = Some(e.to_string());
+                None
+            }
+            Err(e) => exit_failure!("Failed to generate Rust bindings to {}: {}", lib, e),
+        }
+    }
+    /// Prepare the ISPC invocation compiling `src` with `args`, naming the outputs
+    /// after `ispc_fname`. The output is an object unless `emit` selects another kind,
+    /// and is written to the build directory unless an `object` path is given.
+    fn ispc_job<'a>(
+        &self,
+        src: &'a Path,
+        args: &[String],
+        ispc_fname: String,
+        emit: Option<EmitKind>,
+        object: Option<PathBuf>,
+        cache: Option<&Cache>,
+    ) -> IspcJob<'a> {
+        let build_dir = self.get_build_dir();
+        let extension = match emit {
+            Some(kind) => kind.extension(),
+            None if self.emit_cpp => "cpp",
+            None => "o",
         };
-        let mut file = match File::create(bindgen_file) {
-            Ok(f) => f,
-            Err(e) => exit_failure!("Failed to open bindgen mod file for writing: {}", e),
+        let object =
+            object.unwrap_or_else(|| build_dir.join(&ispc_fname).with_extension(extension));
+        let header = build_dir.join(&ispc_fname).with_extension("h");
+        let deps = build_dir.join(&ispc_fname).with_extension("idep");
+        // The additional ISA-specific object files ISPC generates if any
+        let mut isa_objects = Vec::new();
+        if let Some(ref t) = self.get_target_isa() {
+            if t.len() > 1 {
+                for isa in t.iter() {
+                    isa_objects.push(isa_object_path(&object, &isa.lib_suffix()));
+                }
+            }
+        }
+        // ISPC searches the including file's directory for quoted includes itself, but
+        // also put the source's directory on the include path ahead of the user's paths
+        // so includes resolve the same way regardless of where ISPC is run from
+        let src_dir = absolute_path(src.parent().unwrap_or_else(|| Path::new("")));
+        let mut include_src_dir = OsString::from("-I");
+        include_src_dir.push(&src_dir);
+        let mut cmd = Command::new("ispc");
+        cmd.arg(include_src_dir)
+            .args(args)
+            .arg(src)
+            .arg("-o")
+            .arg(&object)
+            .arg("-h")
+            .arg(&header)
+            .arg("-MMM")
+            .arg(&deps);
+        let cmd_line = command_line(&cmd);
+        // Pass the arguments through a response file if the command line gets too long
+        if cmd_line.len() > RESPONSE_FILE_THRESHOLD && !self.dry_run {
+            let rsp = build_dir.join(&ispc_fname).with_extension("rsp");
+            cmd = response_file_command(&cmd, &rsp);
+            self.intermediates.borrow_mut().push(rsp);
+        }
+        // ISPC has no flag to disable colors, but it won't use them on a dumb terminal
+        if self.get_colored_output() == Some(false) {
+            cmd.env("TERM", "dumb");
+        }
+
+        // Skip compiling if the source, its includes and the command haven't changed
+        // since the outputs were last built
+        let args_hash = cache::hash_args(&[&cmd_line, &self.ispc_version_string]);
+        let up_to_date = match cache {
+            Some(c) => {
+                object.exists()
+                    && header.exists()
+                    && isa_objects.iter().all(|o| o.exists())
+                    && c.get(&ispc_fname).is_some_and(|e| {
+                        e.args == args_hash && cache::hash_inputs(src, &deps) == Some(e.inputs)
+                    })
+            }
+            None => false,
         };
-        file.write_all("#[allow(non_camel_case_types,dead_code,non_upper_case_globals,non_snake_case,improper_ctypes)]\n"
-                       .as_bytes()).unwrap();
-        file.write_all(format!("pub mod {lib} {{\n").as_bytes())
-            .unwrap();
-        file.write_all(generated_bindings.as_bytes()).unwrap();
-        file.write_all(b"}").unwrap();
-
-        self.print(&format!("cargo:rustc-link-search=native={}", dst.display()));
-        self.print(&format!("cargo:rustc-env=ISPC_OUT_DIR={}", dst.display()));
+        IspcJob {
+            src,
+            ispc_fname,
+            cmd,
+            cmd_line,
+            object,
+            header,
+            deps,
+            isa_objects,
+            args_hash,
+            up_to_date,
+        }
+    }
+    /// Run the ISPC invocations in `jobs`, running up to `Config::jobs` of them at
+    /// once. The compiled objects are appended to `objects` and the paths to the
+    /// generated headers are returned in the same order as the jobs. If any fail,
+    /// the error for the first failing job in order is reported.
+    fn run_jobs(
+        &self,
+        jobs: Vec<IspcJob>,
+        objects: &mut Vec<PathBuf>,
+        mut cache: Option<&mut Cache>,
+    ) -> Vec<PathBuf> {
+        let mut headers = Vec::new();
+        let mut jobs = jobs.into_iter().peekable();
+        while jobs.peek().is_some() {
+            let mut batch = Vec::new();
+            for mut job in jobs.by_ref().take(self.get_jobs()) {
+                if let Some(ref f) = self.progress {
+                    let runs = 1 + self.emit_kinds.len() + usize::from(self.dual_build);
+                    f(
+                        self.jobs_started.get(),
+                        (self.ispc_files.len() + self.generated_sources.len()) * runs,
+                        job.src,
+                    );
+                }
+                self.jobs_started.set(self.jobs_started.get() + 1);
+                let child = if job.up_to_date {
+                    None
+                } else if self.dry_run {
+                    println!("ispc-rs: would run: {}", job.cmd_line);
+                    None
+                } else {
+                    let child = job
+                        .cmd
+                        .stdout(Stdio::piped())
+                        .stderr(Stdio::piped())
+                        .spawn();
+                    match child {
+                        Ok(child) => Some(child),
+                        Err(e) => exit_failure!(
+                            "Failed to run ISPC on {} ({}), command was:\n{}",
+                            job.src.display(),
+                            e,
+                            job.cmd_line
+                        ),
+                    }
+                };
+                batch.push((job, child));
+            }
+            for (job, child) in batch {
+                if let Some(child) = child {
+                    let output = match child.wait_with_output() {
+                        Ok(output) => output,
+                        Err(e) => exit_failure!(
+                            "Failed to wait for ISPC on {} ({}), command was:\n{}",
+                            job.src.display(),
+                            e,
+                            job.cmd_line
+                        ),
+                    };
+                    self.check_ispc_output(&job, output);
+                    if let Some(ref mut c) = cache {
+                        if let Some(inputs) = cache::hash_inputs(job.src, &job.deps) {
+                            c.insert(
+                                &job.ispc_fname,
+                                cache::Entry {
+                                    inputs,
+                                    args: job.args_hash,
+                                },
+                            );
+                        }
+                    }
+                }
+                objects.push(job.object);
+                objects.extend(job.isa_objects);
+                if !self.dry_run {
+                    // Go this files dependencies and add them to Cargo's watch list
+                    let deps_list = File::open(&job.deps).unwrap_or_else(|_| {
+                        panic!("Failed to open dependencies list for {}", job.src.display())
+                    });
+                    let reader = BufReader::new(deps_list);
+                    for d in reader.lines() {
+                        // Don't depend on the ISPC "stdlib" file which is output as a dependency
+                        let dep_name = d.unwrap();
+                        self.print(&format!("cargo:rerun-if-changed={dep_name}"));
+                    }
+                }
+                headers.push(job.header);
+            }
+        }
+        headers
+    }
+    /// Get the ISPC sources to compile, the files added with `file` followed by the
+    /// sources added with `source_string`, which are written to the build directory
+    fn source_files(&self) -> Vec<PathBuf> {
+        let generated = self
+            .generated_sources
+            .iter()
+            .map(|(name, _)| self.generated_source_path(name));
+        self.ispc_files.iter().cloned().chain(generated).collect()
+    }
+    /// Get the path the source added with `source_string` as `name` is written to
+    fn generated_source_path(&self, name: &str) -> PathBuf {
+        self.get_build_dir().join(name).with_extension("ispc")
+    }
+    /// Write the ISPC sources added with `source_string` to the build directory
+    fn write_generated_sources(&self) {
+        for (name, contents) in &self.generated_sources {
+            let file = self.generated_source_path(name);
+            // Only write the file if it changed to avoid touching the source each build
+            if fs::read_to_string(&file).is_ok_and(|c| c == *contents) {
+                continue;
+            }
+            if let Err(e) = fs::write(&file, contents) {
+                exit_failure!(
+                    "Failed to write generated ISPC source {}: {}",
+                    file.display(),
+                    e
+                );
+            }
+        }
+    }
+    /// Compile the C++ `sources`, emitted by ISPC or added with `cpp_file`, with the
+    /// C++ compiler, returning the paths to the compiled objects
+    fn compile_cpp(&self, sources: &[PathBuf]) -> Vec<PathBuf> {
+        if self.dry_run {
+            for s in sources {
+                println!(
+                    "ispc-rs: would compile {} with the C++ compiler",
+                    s.display()
+                );
+            }
+            return sources.iter().map(|s| s.with_extension("o")).collect();
+        }
+        let objects = self
+            .cc_build()
+            .cpp(true)
+            .debug(self.get_debug())
+            .pic(self.get_pic())
+            .out_dir(self.get_build_dir())
+            .includes(&self.include_paths)
+            // Glue code can include the headers ISPC generated for the kernels
+            .include(self.get_build_dir())
+            .files(sources)
+            .try_compile_intermediates();
+        match objects {
+            Ok(o) => o,
+            Err(e) => exit_failure!("Failed to compile C++ sources: {}", e),
+        }
+    }
+    /// Print the settings the library will be compiled with as Cargo warnings
+    fn print_settings(&self, args: &[String]) {
+        let defines: Vec<String> = self
+            .defines
+            .iter()
+            .map(|(d, v)| match v {
+                Some(v) => format!("{d}={v}"),
+                None => d.clone(),
+            })
+            .collect();
+        let includes: Vec<String> = self
+            .include_paths
+            .iter()
+            .map(|p| absolute_path(p).display().to_string())
+            .collect();
+        let settings = [
+            ("ISPC", self.ispc_version_string.clone()),
+            ("target", self.get_target()),
+            ("debug", self.get_debug().to_string()),
+            ("opt level", self.get_opt_level().to_string()),
+            ("defines", defines.join(" ")),
+            ("include paths", includes.join(" ")),
+            ("output dir", self.get_out_dir().display().to_string()),
+            ("build dir", self.get_build_dir().display().to_string()),
+            ("jobs", self.get_jobs().to_string()),
+            ("pic", self.describe_pic()),
+            ("ISPC args", args.join(" ")),
+        ];
+        for (name, value) in &settings {
+            self.print(&format!("cargo:warning=ispc-rs: {name}: {value}"));
+        }
+    }
+    /// Describe whether position independent code is generated and why
+    fn describe_pic(&self) -> String {
+        let reason = match (&self.library_kind, self.pic) {
+            (LibraryKind::Shared, _) => "required for shared libraries",
+            (_, Some(_)) => "set with Config::pic",
+            (_, None) => "matching the Rust relocation model",
+        };
+        format!("{} ({})", self.get_pic(), reason)
+    }
+    /// Find the target ISAs each function exported in `headers` was compiled for,
+    /// by looking for ISPC's per-target symbols, e.g. `foo_avx2`, in the per-target
+    /// objects written next to the object of each source
+    fn target_variants(
+        &self,
+        objects: &[PathBuf],
+        headers: &[PathBuf],
+    ) -> BTreeMap<String, Vec<String>> {
+        let mut variants = BTreeMap::new();
+        let isas = match self.get_target_isa() {
+            Some(isas) if isas.len() > 1 => isas,
+            _ => return variants,
+        };
+        let mut suffixes: Vec<String> = isas.iter().map(|isa| isa.lib_suffix()).collect();
+        suffixes.sort();
+        suffixes.dedup();
+        for (object, header) in objects.iter().zip(headers) {
+            let objects: Vec<(&String, Vec<u8>)> = suffixes
+                .iter()
+                .filter_map(|suffix| {
+                    let isa_object = isa_object_path(object, suffix);
+                    fs::read(isa_object).ok().map(|contents| (suffix, contents))
+                })
+                .collect();
+            for f in header::exported_functions(header) {
+                let found = objects
+                    .iter()
+                    .filter(|(suffix, contents)| {
+                        let symbol = format!("{f}_{suffix}");
+                        contents
+                            .windows(symbol.len())
+                            .any(|w| w == symbol.as_bytes())
+                    })
+                    .map(|(suffix, _)| suffix.to_string())
+                    .collect();
+                variants.insert(f, found);
+            }
+        }
+        variants
+    }
+    /// Forward ISPC's diagnostics for `job` as Cargo warnings and exit if it failed
+    fn check_ispc_output(&self, job: &IspcJob, output: Output) {
+        if !output.stderr.is_empty() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            for l in stderr.lines() {
+                self.print(&format!("cargo:warning=(ISPC) {l}"));
+            }
+        }
+        if !output.status.success() {
+            if crashed(&output.status) {
+                exit_failure!(
+                    "ISPC crashed (internal compiler error) on {} ({}), command was:\n{}",
+                    job.src.display(),
+                    output.status,
+                    job.cmd_line
+                );
+            }
+            exit_failure!(
+                "Compilation errors in ISPC source file {}, command was:\n{}",
+                job.src.display(),
+                job.cmd_line
+            );
+        }
+    }
+    /// Set whether the ISPC compiler version should be logged as a Cargo warning
+    /// when compiling, which is useful to include in bug reports.
+    pub fn print_compiler_version(&mut self, print: bool) -> &mut Config {
+        self.print_compiler_version = print;
+        self
+    }
+    /// Get the object files linked into the library by the last call to `compile`,
+    /// including the per-target objects of multi-target builds and any compiled
+    /// C++, e.g. to use them in another build step. Empty before `compile` is called.
+    pub fn objects(&self) -> Vec<PathBuf> {
+        self.objects.borrow().clone()
+    }
+    /// Get the C headers ISPC generated for each source file by the last call to
+    /// `compile`, e.g. to run other documentation or binding tools over them. With
+    /// `dual_build` the header of each source's debug variant follows its own.
+    /// Empty before `compile` is called.
+    pub fn headers(&self) -> Vec<PathBuf> {
+        self.headers.borrow().clone()
+    }
+    /// Get the objects ISPC wrote for each target ISA by the last call to `compile`
+    /// when compiling for multiple ISAs, keyed by the name ISPC gives the ISA in the
+    /// object names, e.g. `avx2` for `foo_ispc_avx2.o`. The objects for each ISA are
+    /// in the order the sources were added. Empty when compiling for a single target,
+    /// or before `compile` is called.
+    pub fn isa_objects(&self) -> BTreeMap<String, Vec<PathBuf>> {
+        self.isa_objects.borrow().clone()
+    }
+    /// Get the LLVM bitcode files written by the last call to `compile` when
+    /// `emit_bitcode` is enabled, e.g. to run further LLVM passes on the kernels or
+    /// for cross-language LTO. Empty before `compile` is called.
+    pub fn bitcode(&self) -> Vec<PathBuf> {
+        self.bitcode.borrow().clone()
+    }
+    /// Get the error bindgen failed with in the last call to `compile`, when the
+    /// failure was allowed with `allow_bindgen_failure`, e.g. to fall back to other
+    /// bindings only when they couldn't be generated. None if the bindings were
+    /// generated, or before `compile` is called.
+    pub fn bindgen_error(&self) -> Option<String> {
+        self.bindgen_error.borrow().clone()
     }
     /// Get the ISPC compiler version.
     pub fn ispc_version(&self) -> &Version {
         &self.ispc_version
     }
-    /// Link the ISPC code into a static library on Unix using `ar`
+    /// Emit the link directives for the Intel SVML runtime, which provides the
+    /// `__svml_*` functions called by ISPC code compiled with `MathLib::SVML`
+    fn link_svml(&self) {
+        if let Some(ref p) = self.svml_path {
+            self.print(&format!(
+                "cargo:rustc-link-search=native={}",
+                link_search_dir(p).display()
+            ));
+        }
+        if self.get_target().contains("windows") {
+            self.print(&"cargo:rustc-link-lib=svml_dispmd");
+        } else {
+            self.print(&"cargo:rustc-link-lib=svml");
+            self.print(&"cargo:rustc-link-lib=irc");
+        }
+    }
+    /// Get the path to the file the Rust bindings to `lib` are written to, e.g. to
+    /// copy them into the source tree or pass them on to other build scripts.
+    /// The file only exists after `compile` if bindings were generated.
+    pub fn bindings_path(&self, lib: &str) -> PathBuf {
+        self.get_out_dir()
+            .join(self.bindings_name.as_deref().unwrap_or(lib))
+            .with_extension("rs")
+    }
+    /// Print the `links` metadata for dependents of the package, see
+    /// `print_links_metadata`
+    fn write_links_metadata(&self, libfile: &str, bindings: Option<&Path>) {
+        if !self.print_links_metadata || env::var_os("CARGO_MANIFEST_LINKS").is_none() {
+            return;
+        }
+        println!("cargo:root={}", self.get_out_dir().display());
+        println!("cargo:lib={libfile}");
+        if let Some(b) = bindings {
+            println!("cargo:bindings={}", b.display());
+        }
+        if let Some(dir) = self
+            .c_header
+            .as_ref()
+            .and_then(|h| absolute_path(h).parent().map(Path::to_path_buf))
+        {
+            println!("cargo:include={}", dir.display());
+        }
+    }
+    /// Get the path to the library file which `assemble` or `link_shared` will
+    /// produce for `lib`
+    fn library_path(&self, lib: &str) -> PathBuf {
+        let file = match self.library_kind {
+            LibraryKind::Static if cfg!(windows) => format!("{lib}.lib"),
+            LibraryKind::Static => format!("lib{lib}.a"),
+            LibraryKind::Shared if cfg!(windows) => format!("{lib}.dll"),
+            LibraryKind::Shared if self.get_target().contains("apple") => {
+                format!("lib{lib}.dylib")
+            }
+            LibraryKind::Shared => format!("lib{lib}.so"),
+        };
+        self.get_out_dir().join(file)
+    }
+    /// Get the commands to run to link the ISPC objects into the library, which
+    /// must all succeed
+    fn link_commands(&self, lib: &str, objects: &[PathBuf]) -> Vec<Command> {
+        let mut cmds = match self.library_kind {
+            LibraryKind::Static => self.assemble(lib, objects),
+            LibraryKind::Shared => vec![self.link_shared(lib, objects)],
+        };
+        if self.strip {
+            if let Some(strip) = self.strip_command(lib) {
+                // Strip before ranlib indexes the archive on Apple targets
+                let at = match self.library_kind {
+                    LibraryKind::Static if cmds.len() > 1 => cmds.len() - 1,
+                    _ => cmds.len(),
+                };
+                cmds.insert(at, strip);
+            }
+        }
+        cmds
+    }
+    /// Get the command stripping the debug info from `lib` with the `strip` tool
+    /// matching the target's archiver, e.g. `llvm-strip` for `llvm-ar` or
+    /// `aarch64-linux-gnu-strip` for `aarch64-linux-gnu-ar`. Returns None with a
+    /// warning if the tool can't be found.
     #[cfg(unix)]
-    fn assemble(&self, lib: &str, objects: &[PathBuf]) -> ExitStatus {
-        Command::new("ar")
-            .arg("crus")
-            .arg(format!("lib{lib}.a"))
-            .args(objects)
-            .current_dir(self.get_out_dir())
-            .status()
-            .unwrap()
+    fn strip_command(&self, lib: &str) -> Option<Command> {
+        let strip = match self.cc_build().try_get_archiver() {
+            Ok(ar) => {
+                let ar = PathBuf::from(ar.get_program());
+                let name = ar.file_name().unwrap_or_default().to_string_lossy();
+                match name.strip_suffix("ar") {
+                    Some(prefix) => ar.with_file_name(format!("{prefix}strip")),
+                    None => PathBuf::from("strip"),
+                }
+            }
+            Err(_) => PathBuf::from("strip"),
+        };
+        if !program_exists(&strip) {
+            self.print(&format!(
+                "cargo:warning=ispc-rs: Not stripping {lib}, {} wasn't found",
+                strip.display()
+            ));
+            return None;
+        }
+        // -S only removes the debug info, unlike a plain strip it works on both
+        // archives and shared libraries without removing the exported symbols
+        let mut cmd = Command::new(strip);
+        cmd.arg("-S").arg(self.library_path(lib));
+        Some(cmd)
+    }
+    /// MSVC keeps the debug info in separate PDB files, so there's nothing to strip
+    #[cfg(windows)]
+    fn strip_command(&self, lib: &str) -> Option<Command> {
+        self.print(&format!(
+            "cargo:warning=ispc-rs: Not stripping {lib}, stripping isn't supported on Windows"
+        ));
+        None
+    }
+    /// Get a `cc` build configured for the target and optimization level, see
+    /// `target_cc_build`
+    fn cc_build(&self) -> cc::Build {
+        let mut build = target_cc_build(&self.get_target());
+        build.opt_level(self.get_opt_level());
+        build
+    }
+    /// Link the ISPC code into a static library on Unix using the target's `ar`
+    #[cfg(unix)]
+    fn assemble(&self, lib: &str, objects: &[PathBuf]) -> Vec<Command> {
+        let archive = format!("lib{lib}.a");
+        let build = self.cc_build();
+        let mut ar = archiver(&build);
+        ar.current_dir(self.get_out_dir());
+        if !self.get_target().contains("apple") {
+            ar.arg("crus").arg(&archive).args(objects);
+            return vec![ar];
+        }
+        // The symbol table written by `ar s` isn't always one the Mach-O linker
+        // accepts, e.g. with GNU ar when cross compiling, so index it with ranlib
+        ar.arg("crs").arg(&archive).args(objects);
+        let mut ranlib = match build.try_get_ranlib() {
+            Ok(ranlib) => ranlib,
+            Err(e) => exit_failure!("Failed to find ranlib for the target: {}", e),
+        };
+        ranlib.arg(&archive).current_dir(self.get_out_dir());
+        vec![ar, ranlib]
     }
     /// Link the ISPC code into a static library on Windows using `lib.exe`
     #[cfg(windows)]
-    fn assemble(&self, lib: &str, objects: &[PathBuf]) -> ExitStatus {
-        let target = self.get_target();
-        let mut lib_cmd = cc::windows_registry::find_tool(&target, "lib.exe")
-            .expect("Failed to find lib.exe for MSVC toolchain, aborting")
-            .to_command();
+    fn assemble(&self, lib: &str, objects: &[PathBuf]) -> Vec<Command> {
+        let mut lib_cmd = match self.msvc_lib_tool {
+            Some(ref tool) => Command::new(tool),
+            None => match self.cc_build().try_get_archiver() {
+                Ok(tool) => tool,
+                Err(e) => exit_failure!(
+                    "Failed to find lib.exe for the MSVC toolchain ({}), set its path \
+                     with Config::msvc_lib_tool",
+                    e
+                ),
+            },
+        };
         lib_cmd
             .arg(format!("/OUT:{lib}.lib"))
             .args(objects)
-            .current_dir(self.get_out_dir())
-            .status()
-            .unwrap()
+            .current_dir(self.get_out_dir());
+        vec![lib_cmd]
+    }
+    /// Link the ISPC code into a shared library on Unix using the C compiler
+    #[cfg(unix)]
+    fn link_shared(&self, lib: &str, objects: &[PathBuf]) -> Command {
+        let mut cmd = self.cc_build().get_compiler().to_command();
+        if self.get_target().contains("apple") {
+            cmd.arg("-dynamiclib");
+        } else {
+            cmd.arg("-shared");
+        }
+        if let Some(ref script) = self.linker_script {
+            let mut arg = OsString::from("-Wl,-T,");
+            arg.push(absolute_path(script));
+            cmd.arg(arg);
+        }
+        cmd.arg("-o")
+            .arg(self.library_path(lib))
+            .args(objects)
+            .current_dir(self.get_out_dir());
+        cmd
+    }
+    /// Link the ISPC code into a DLL and its import library on Windows using `link.exe`,
+    /// or the linker next to the tool set with `msvc_lib_tool`
+    #[cfg(windows)]
+    fn link_shared(&self, lib: &str, objects: &[PathBuf]) -> Command {
+        let mut link_cmd = match self.msvc_lib_tool {
+            Some(ref tool) => {
+                let llvm = tool
+                    .file_stem()
+                    .is_some_and(|s| s.to_string_lossy().starts_with("llvm-lib"));
+                Command::new(tool.with_file_name(if llvm { "lld-link.exe" } else { "link.exe" }))
+            }
+            None => match cc::windows_registry::find_tool(&self.get_target(), "link.exe") {
+                Some(tool) => tool.to_command(),
+                None => exit_failure!(
+                    "Failed to find link.exe for the MSVC toolchain, set the path to the \
+                     lib.exe next to it with Config::msvc_lib_tool"
+                ),
+            },
+        };
+        link_cmd
+            .arg("/DLL")
+            .arg(format!("/OUT:{lib}.dll"))
+            .arg(format!("/IMPLIB:{lib}.lib"))
+            .args(objects)
+            .arg("msvcrt.lib")
+            .current_dir(self.get_out_dir());
+        link_cmd
     }
     /// Generate a single header that includes all of our ISPC headers which we can
     /// pass to bindgen
@@ -452,21 +2708,203 @@ impl Config {
             .get_build_dir()
             .join(format!("_{lib}_ispc_bindgen_header.h"));
         let mut include_file = File::create(&bindgen_header).unwrap();
+        self.intermediates.borrow_mut().push(bindgen_header.clone());
 
         writeln!(include_file, "#include <stdint.h>").unwrap();
         writeln!(include_file, "#include <stdbool.h>").unwrap();
+        // Headers shared with ISPC code may use its type qualifiers and integer types
+        if self.bindgen_only.is_some() {
+            writeln!(include_file, "#define uniform").unwrap();
+            writeln!(include_file, "#define varying").unwrap();
+            for bits in &[8, 16, 32, 64] {
+                writeln!(include_file, "typedef int{bits}_t int{bits};").unwrap();
+                writeln!(include_file, "typedef uint{bits}_t uint{bits};").unwrap();
+            }
+        }
 
+        // Each ISPC header declares the structs used by the functions it exports,
+        // so kernels sharing a struct will each declare it. Include a copy of the header
+        // with the repeated declarations removed so bindgen sees each type only once.
+        let mut types = HashMap::new();
         for h in headers {
-            writeln!(include_file, "#include \"{}\"", h.display()).unwrap();
+            let contents = fs::read_to_string(h)
+                .unwrap_or_else(|e| panic!("Failed to read ISPC header {}: {}", h.display(), e));
+            match header::remove_duplicate_types(&contents, &mut types) {
+                Some(deduped) => {
+                    // Write the copy to the build directory, the header may be a user's file
+                    let deduped_header = self
+                        .get_build_dir()
+                        .join(h.file_name().unwrap())
+                        .with_extension("dedup.h");
+                    fs::write(&deduped_header, deduped).unwrap();
+                    writeln!(include_file, "#include \"{}\"", deduped_header.display()).unwrap();
+                    self.intermediates.borrow_mut().push(deduped_header);
+                }
+                None => writeln!(include_file, "#include \"{}\"", h.display()).unwrap(),
+            }
+        }
+        // Declare the globals after the headers, which declare the types they may use
+        for (_, decl) in &self.globals {
+            writeln!(include_file, "extern {decl};").unwrap();
         }
         bindgen_header
     }
+    /// Remove the intermediate files written by the current compile from the build
+    /// directory, unless they should be kept (see `keep_intermediates`). Files
+    /// written by other build steps or other `Config`s are left alone.
+    fn remove_intermediates(&self) {
+        let keep = self
+            .keep_intermediates
+            .unwrap_or_else(|| env::var("PROFILE").as_deref() != Ok("release"));
+        if keep {
+            return;
+        }
+        for path in self.intermediates.borrow_mut().drain(..) {
+            let _ = fs::remove_file(path);
+        }
+    }
+    /// Write the combined header for C and C++ code using `lib` to `path`, with the
+    /// contents of each of the ISPC `headers` included in it
+    fn write_c_header(&self, lib: &str, headers: &[PathBuf], path: &Path) {
+        let guard: String = format!("{lib}_ispc_h")
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let mut contents = format!(
+            "// Declarations of the functions exported from the ISPC library {lib}, \
+             generated by ispc-rs\n\
+             #ifndef {guard}\n#define {guard}\n\n"
+        );
+        let mut types = HashMap::new();
+        for h in headers {
+            let header = fs::read_to_string(h)
+                .unwrap_or_else(|e| panic!("Failed to read ISPC header {}: {}", h.display(), e));
+            match header::remove_duplicate_types(&header, &mut types) {
+                Some(deduped) => contents.push_str(&deduped),
+                None => contents.push_str(&header),
+            }
+            contents.push('\n');
+        }
+        // Declare the globals like ISPC declares the functions, in the ispc namespace for C++
+        if !self.globals.is_empty() {
+            contents.push_str("#ifdef __cplusplus\nnamespace ispc { extern \"C\" {\n#endif\n");
+            for (_, decl) in &self.globals {
+                contents.push_str(&format!("extern {decl};\n"));
+            }
+            contents.push_str("#ifdef __cplusplus\n} }\n#endif\n\n");
+        }
+        contents.push_str(&format!("#endif // {guard}\n"));
+        if let Some(dir) = path.parent() {
+            if let Err(e) = fs::create_dir_all(dir) {
+                exit_failure!("Failed to create directory {}: {}", dir.display(), e);
+            }
+        }
+        if let Err(e) = fs::write(path, contents) {
+            exit_failure!("Failed to write C header {}: {}", path.display(), e);
+        }
+    }
+    /// Check that the installed ISPC compiler supports the flags we'll be passing,
+    /// exits with an error naming the version required if not.
+    fn check_flag_support(&self, args: &[String]) {
+        let require = |name: &str, min: (u64, u64, u64)| {
+            let min_ver = Version::new(min.0, min.1, min.2);
+            if self.ispc_version < min_ver {
+                exit_failure!(
+                    "Error: {} requires ISPC >= {}, but the installed ISPC is {}",
+                    name,
+                    min_ver,
+                    self.ispc_version
+                );
+            }
+        };
+        for a in args {
+            for &(flag, min) in FLAG_MIN_VERSIONS {
+                if a.starts_with(flag) {
+                    require(flag, min);
+                }
+            }
+            for &(flag, max) in FLAG_MAX_VERSIONS {
+                let max_ver = Version::new(max.0, max.1, max.2);
+                if a.starts_with(flag) && self.ispc_version >= max_ver {
+                    exit_failure!(
+                        "Error: {} was removed in ISPC {}, but the installed ISPC is {}",
+                        flag,
+                        max_ver,
+                        self.ispc_version
+                    );
+                }
+            }
+            if let Some(isas) = a.strip_prefix("--target=") {
+                for isa in isas.split(',') {
+                    for &(name, min) in ISA_MIN_VERSIONS {
+                        if isa.starts_with(name) {
+                            require(isa, min);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    /// Check that the target ISAs in `args` can be compiled for the architecture,
+    /// exits with an error if not instead of letting ISPC fail with a confusing one.
+    /// The architecture is the last one passed with `--arch`, which is picked for
+    /// the Rust target unless it's set explicitly.
+    fn check_isa_architecture(&self, args: &[String]) {
+        let Some(arch) = args.iter().rev().find_map(|a| a.strip_prefix("--arch=")) else {
+            return;
+        };
+        // ISPC accepts both spellings of x86_64
+        let arch = if arch == "x86-64" { "x86_64" } else { arch };
+        for isas in args.iter().filter_map(|a| a.strip_prefix("--target=")) {
+            for isa in isas.split(',') {
+                let Some(&(_, archs)) = ISA_ARCHITECTURES
+                    .iter()
+                    .find(|(prefix, _)| isa.starts_with(prefix))
+                else {
+                    continue;
+                };
+                // Xe ISAs are compiled for the GPU while the arch picked for the Rust
+                // target is the host's, so only check them against an explicit arch
+                if self.architecture.is_none() && archs == ["xe64"] {
+                    continue;
+                }
+                if !archs.contains(&arch) {
+                    exit_failure!(
+                        "Error: The ISPC target ISA {} can't be compiled for the {} \
+                         architecture (building for {}), it requires {}",
+                        isa,
+                        arch,
+                        self.get_target(),
+                        archs.join(" or ")
+                    );
+                }
+            }
+        }
+    }
     /// Build up list of basic args for each target, debug, opt level, etc.
     fn default_args(&self) -> Vec<String> {
-        let mut ispc_args = Vec::new();
-        let opt_level = self.get_opt_level();
-        if self.get_debug() {
+        self.ispc_args(self.get_debug(), self.get_opt_level())
+    }
+    /// Build up the list of args for each target with the debug and opt level passed
+    fn ispc_args(&self, debug: bool, opt_level: u32) -> Vec<String> {
+        // ISPC uses the last value given for a flag, so the flags from the environment
+        // come first for the settings made on the Config to take precedence
+        let mut ispc_args: Vec<String> = match env::var("ISPC_FLAGS") {
+            Ok(flags) => flags.split_whitespace().map(String::from).collect(),
+            Err(_) => Vec::new(),
+        };
+        if debug {
             ispc_args.push(String::from("-g"));
+            match self.debug_format {
+                Some(DebugFormat::CodeView) | None => {}
+                Some(ref f) => ispc_args.push(f.to_string()),
+            }
         }
         if let Some(ref c) = self.cpu_target {
             ispc_args.push(c.to_string());
@@ -483,17 +2921,32 @@ impl Config {
             ispc_args.push(String::from("-O") + &opt_level.to_string());
         }
 
-        // If we're on Unix we need position independent code
-        if cfg!(unix) {
+        if self.get_pic() {
             ispc_args.push(String::from("--pic"));
         }
+        // Functions in a DLL must be marked for export to be visible outside it
+        if let LibraryKind::Shared = self.library_kind {
+            if self.get_target().contains("windows") {
+                ispc_args.push(String::from("--dllexport"));
+            }
+        }
+        match self.get_vectorcall() {
+            Some(true) => ispc_args.push(String::from("--vectorcall")),
+            Some(false) => ispc_args.push(String::from("--no-vectorcall")),
+            None => {}
+        }
+        if let Some(ref m) = self.code_model {
+            ispc_args.push(format!("--mcmodel={m}"));
+        }
         let target = self.get_target();
-        if target.starts_with("i686") {
-            ispc_args.push(String::from("--arch=x86"));
-        } else if target.starts_with("x86_64") {
-            ispc_args.push(String::from("--arch=x86-64"));
-        } else if target.starts_with("aarch64") {
-            ispc_args.push(String::from("--arch=aarch64"));
+        let arch = target_arch(&target);
+        if let Some(a) = arch {
+            ispc_args.push(format!("--arch={a}"));
+        }
+        if self.no_cpp && !self.defines.is_empty() {
+            self.print(
+                &"cargo:warning=ispc-rs: Defines are ignored when the preprocessor is disabled by no_cpp",
+            );
         }
         for d in &self.defines {
             match d.1 {
@@ -511,8 +2964,16 @@ impl Config {
         for o in &self.optimization_opts {
             ispc_args.push(o.to_string());
         }
-        for p in &self.include_paths {
-            ispc_args.push(format!("-I{}", p.display()));
+        // Headers generated by the build script or another build step are found in
+        // OUT_DIR, searched after the user's paths
+        let out_dir = env::var_os("OUT_DIR").map(PathBuf::from);
+        for p in self.include_paths.iter().chain(out_dir.as_ref()) {
+            // The arguments are hashed and printed as strings, so the path must be UTF-8
+            let p = absolute_path(p);
+            match p.to_str() {
+                Some(p) => ispc_args.push(format!("-I{p}")),
+                None => exit_failure!("ISPC include path {} must be valid UTF-8", p.display()),
+            }
         }
         if self.no_omit_frame_ptr {
             ispc_args.push(String::from("--no-omit-frame-pointer"));
@@ -532,6 +2993,9 @@ impl Config {
         if self.woff {
             ispc_args.push(String::from("--woff"));
         }
+        if self.get_colored_output() == Some(true) {
+            ispc_args.push(String::from("--colored-output"));
+        }
         if self.wno_perf {
             ispc_args.push(String::from("--wno-perf"));
         }
@@ -541,14 +3005,20 @@ impl Config {
         if self.enable_llvm_intrinsics {
             ispc_args.push(String::from("--enable-llvm-intrinsics"));
         }
-        if let Some(ref t) = self.target_isa {
+        if self.emit_cpp {
+            // The generic targets are the only ones ISPC can emit C++ for, the code it
+            // emits includes a header implementing the target's vector intrinsics
+            ispc_args.push(String::from("--emit-c++"));
+            ispc_args.push(String::from("--target=generic-16"));
+            ispc_args.push(String::from("--c++-include-file=generic-16.h"));
+        } else if let Some(ref t) = self.get_target_isa() {
             let mut isa_str = String::from("--target=");
             isa_str.push_str(&t[0].to_string());
             for isa in t.iter().skip(1) {
                 isa_str.push_str(&format!(",{}", isa));
             }
             ispc_args.push(isa_str);
-        } else if target.starts_with("aarch64") {
+        } else if matches!(arch, Some("arm") | Some("aarch64")) {
             // For arm we may need to override the default target ISA,
             // e.g. on macOS with ISPC running in Rosetta, ISPC will default to
             // SSE4, but we need NEON
@@ -560,50 +3030,183 @@ impl Config {
         }
         if let Some(ref o) = self.target_os {
             ispc_args.push(o.to_string());
+        } else if target.contains("apple") {
+            // ISPC defaults to the OS it's running on, so when cross compiling or
+            // running under Rosetta on Apple Silicon it must be told to emit Mach-O
+            if target.contains("-ios") {
+                ispc_args.push(TargetOS::Ios.to_string());
+            } else if target.contains("-darwin") {
+                ispc_args.push(TargetOS::Macos.to_string());
+            }
         }
         ispc_args
     }
+    /// Get the clang arguments to generate the bindings with, adding the target
+    /// triple if the user hasn't specified one
+    fn bindgen_clang_args(&self) -> Vec<String> {
+        let mut args = self.clang_args.clone();
+        if !args
+            .iter()
+            .any(|a| a == "-target" || a.starts_with("--target="))
+        {
+            args.push(format!("--target={}", self.get_target()));
+        }
+        args
+    }
     /// Returns the user-set output directory if they've set one, otherwise
-    /// returns env("OUT_DIR")
+    /// returns env("OUT_DIR"). Exits with an error if neither is set, e.g. when
+    /// used outside of a build script
     fn get_out_dir(&self) -> PathBuf {
-        let p = self
-            .out_dir
-            .clone()
-            .unwrap_or_else(|| env::var_os("OUT_DIR").map(PathBuf::from).unwrap());
-        if p.is_relative() {
-            env::current_dir().unwrap().join(p)
-        } else {
-            p
-        }
+        let p = match self.out_dir.clone() {
+            Some(p) => p,
+            None => match env::var_os("OUT_DIR") {
+                Some(p) => PathBuf::from(p),
+                None => exit_failure!(
+                    "Error: OUT_DIR isn't set, set the output directory with \
+                     Config::out_dir when compiling outside of a build script"
+                ),
+            },
+        };
+        absolute_path(&p)
     }
-    /// Returns the default cargo output dir for build scripts (env("OUT_DIR"))
+    /// Returns the directory to write intermediate build files to, the default
+    /// cargo output dir for build scripts (env("OUT_DIR")), or the output
+    /// directory outside of a build script
     fn get_build_dir(&self) -> PathBuf {
-        env::var_os("OUT_DIR").map(PathBuf::from).unwrap()
+        self.build_dir
+            .clone()
+            .or_else(|| env::var_os("OUT_DIR").map(PathBuf::from))
+            .unwrap_or_else(|| self.get_out_dir())
+    }
+    /// Returns the user-set number of parallel ISPC processes if they've set one,
+    /// otherwise env("NUM_JOBS") or the number of CPUs
+    fn get_jobs(&self) -> usize {
+        self.jobs.unwrap_or_else(|| {
+            if let Some(n) = env::var("NUM_JOBS").ok().and_then(|n| n.parse().ok()) {
+                return std::cmp::max(n, 1);
+            }
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1)
+        })
     }
     /// Returns the user-set debug flag if they've set one, otherwise returns
-    /// env("DEBUG")
+    /// env("DEBUG"), or false outside of a build script where it isn't set
     fn get_debug(&self) -> bool {
         self.debug
-            .unwrap_or_else(|| env::var("DEBUG").map(|x| x == "true").unwrap())
+            .unwrap_or_else(|| env::var("DEBUG").map(|x| x == "true").unwrap_or(false))
+    }
+    /// Returns the user-set target ISAs if they've set any, otherwise the
+    /// ones listed in env("ISPC_TARGET") if it's set
+    fn get_target_isa(&self) -> Option<Vec<TargetISA>> {
+        if self.target_isa.is_some() {
+            return self.target_isa.clone();
+        }
+        let isas = env::var("ISPC_TARGET").ok()?;
+        let parsed = isas
+            .split(',')
+            .map(|isa| isa.trim().parse::<TargetISA>())
+            .collect::<Result<Vec<_>, _>>();
+        match parsed {
+            Ok(t) if !t.is_empty() => Some(t),
+            Ok(_) => None,
+            Err(e) => exit_failure!("Invalid ISPC_TARGET: {}", e),
+        }
     }
     /// Returns the user-set optimization level if they've set one, otherwise
-    /// returns env("OPT_LEVEL")
+    /// returns env("ISPC_OPT_LEVEL") or env("OPT_LEVEL"), where the size
+    /// optimization levels "s" and "z" map to 1. Defaults to 0 outside of a build
+    /// script where neither is set
     fn get_opt_level(&self) -> u32 {
         self.opt_level.unwrap_or_else(|| {
-            let opt = env::var("OPT_LEVEL").unwrap();
-            opt.parse::<u32>().unwrap()
+            if let Ok(opt) = env::var("ISPC_OPT_LEVEL") {
+                match opt.parse::<u32>() {
+                    Ok(o) => return o,
+                    Err(_) => exit_failure!("Invalid ISPC_OPT_LEVEL '{}'", opt),
+                }
+            }
+            let opt = match env::var("OPT_LEVEL") {
+                Ok(opt) => opt,
+                Err(_) => return 0,
+            };
+            match opt.as_str() {
+                // ISPC has no size optimization level, but -O1 avoids the
+                // optimizations which increase code size
+                "s" | "z" => 1,
+                _ => match opt.parse::<u32>() {
+                    Ok(o) => o,
+                    Err(_) => exit_failure!("Unrecognized OPT_LEVEL '{}'", opt),
+                },
+            }
         })
     }
+    /// Returns whether ISPC's colored output should be forced on or off, or None
+    /// to let ISPC decide
+    fn get_colored_output(&self) -> Option<bool> {
+        match self.color {
+            ColorChoice::Always => Some(true),
+            ColorChoice::Never => Some(false),
+            ColorChoice::Auto => match env::var("CARGO_TERM_COLOR").as_deref() {
+                Ok("always") => Some(true),
+                Ok("never") => Some(false),
+                _ => None,
+            },
+        }
+    }
+    /// Returns the user's vectorcall setting if they've set one and the target
+    /// supports it, otherwise None to use ISPC's default
+    fn get_vectorcall(&self) -> Option<bool> {
+        let target = self.get_target();
+        if target.starts_with("x86_64") && target.contains("windows") {
+            self.vectorcall
+        } else {
+            None
+        }
+    }
+    /// Returns the user-set PIC setting if they've set one, otherwise matches
+    /// the relocation model Rust is compiling with.
+    fn get_pic(&self) -> bool {
+        // Shared libraries must always be position independent
+        if let LibraryKind::Shared = self.library_kind {
+            return true;
+        }
+        self.pic.unwrap_or_else(|| self.rust_pic())
+    }
+    /// Returns whether Rust is compiling position independent code for the target
+    fn rust_pic(&self) -> bool {
+        match rustc_relocation_model().as_deref() {
+            Some("static") | Some("dynamic-no-pic") => false,
+            Some(_) => true,
+            // Rust defaults to position independent code everywhere but Windows,
+            // while bare-metal targets typically have no loader to relocate the code
+            None => {
+                let target = self.get_target();
+                !target.contains("windows") && !target.contains("-none")
+            }
+        }
+    }
     /// Returns the user-set target triple if they're set one, otherwise
-    /// returns env("TARGET")
+    /// returns env("TARGET"), or the host outside of a build script
     fn get_target(&self) -> String {
         self.target
             .clone()
-            .unwrap_or_else(|| env::var("TARGET").unwrap())
+            .or_else(|| env::var("TARGET").ok())
+            .unwrap_or_else(host_target)
     }
-    /// Print out cargo metadata if enabled
+    /// Print out cargo metadata if enabled, the directives which can be toggled
+    /// individually are checked against their own setting
     fn print<T: Display>(&self, s: &T) {
-        if self.cargo_metadata {
+        let s = s.to_string();
+        let enabled = if s.starts_with("cargo:rustc-link-search=") {
+            self.print_search_paths
+        } else if s.starts_with("cargo:rustc-link-lib=") {
+            self.print_link_libs
+        } else if s.starts_with("cargo:rerun-if-") {
+            self.print_rerun_if_changed
+        } else {
+            self.cargo_metadata
+        };
+        if enabled {
             println!("{s}");
         }
     }
@@ -614,3 +3217,453 @@ impl Default for Config {
         Config::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::panic::{self, AssertUnwindSafe};
+    use std::path::Component;
+    use std::sync::Mutex;
+
+    /// The environment variables read when working out the ISPC arguments, which
+    /// are cleared for each test so the results don't depend on the caller's
+    const ENV_VARS: &[&str] = &[
+        "CARGO_CFG_RELOCATION_MODEL",
+        "CARGO_CFG_TARGET_FEATURE",
+        "CARGO_ENCODED_RUSTFLAGS",
+        "CARGO_TERM_COLOR",
+        "DEBUG",
+        "ISPC_FLAGS",
+        "ISPC_OPT_LEVEL",
+        "ISPC_TARGET",
+        "NUM_JOBS",
+        "OPT_LEVEL",
+        "OUT_DIR",
+        "TARGET",
+    ];
+
+    /// Tests run in parallel, so the ones using the environment take turns
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Run `f` with the variables in `ENV_VARS` cleared and `vars` set, or removed
+    /// if their value is None, and restore the environment afterwards
+    fn with_env<R>(vars: &[(&str, Option<&str>)], f: impl FnOnce() -> R) -> R {
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let saved: Vec<(&str, Option<OsString>)> =
+            ENV_VARS.iter().map(|v| (*v, env::var_os(v))).collect();
+        for v in ENV_VARS {
+            env::remove_var(v);
+        }
+        for (var, value) in vars {
+            match value {
+                Some(value) => env::set_var(var, value),
+                None => env::remove_var(var),
+            }
+        }
+        let result = panic::catch_unwind(AssertUnwindSafe(f));
+        for (var, _) in vars {
+            env::remove_var(var);
+        }
+        for (var, value) in saved {
+            if let Some(value) = value {
+                env::set_var(var, value);
+            }
+        }
+        result.unwrap_or_else(|e| panic::resume_unwind(e))
+    }
+
+    /// A `Config` for ISPC 1.23 building for `target`, which doesn't need ISPC installed
+    fn config(target: &str) -> Config {
+        let mut cfg = Config::with_version_string(
+            "Intel(r) Implicit SPMD Program Compiler (Intel(r) ISPC), 1.23.0 (build commit)",
+        );
+        cfg.target(target).out_dir(env::temp_dir());
+        cfg
+    }
+
+    /// The arguments `cfg` passes to ISPC, with the environment cleared
+    fn default_args(cfg: &Config) -> Vec<String> {
+        with_env(&[], || cfg.default_args())
+    }
+
+    #[test]
+    fn defaults_without_cargo_env() {
+        let dir = env::temp_dir();
+        let mut cfg = Config::with_version_string("ISPC, 1.23.0");
+        cfg.out_dir(&dir);
+        let (args, target, build_dir) = with_env(&[], || {
+            (cfg.default_args(), cfg.get_target(), cfg.get_build_dir())
+        });
+        assert_eq!(target, host_target());
+        assert_eq!(build_dir, absolute_path(&dir));
+        assert!(args.iter().any(|a| a == "-O0"), "{args:?}");
+        assert!(!args.iter().any(|a| a == "-g"), "{args:?}");
+    }
+
+    #[test]
+    fn remove_only_own_intermediates() {
+        let dir = env::temp_dir().join(format!("ispc-rs-intermediates-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let own = dir.join("foo_ispc.rsp");
+        let other = dir.join("other.rsp");
+        for f in [&own, &other] {
+            fs::write(f, "").unwrap();
+        }
+        let mut cfg = config("x86_64-unknown-linux-gnu");
+        cfg.out_dir(&dir).keep_intermediates(false);
+        cfg.intermediates.borrow_mut().push(own.clone());
+        cfg.remove_intermediates();
+        assert!(!own.exists());
+        assert!(other.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn no_cpp_args() {
+        let mut cfg = config("x86_64-unknown-linux-gnu");
+        cfg.add_define("FOO", None).no_cpp();
+        let args = default_args(&cfg);
+        assert!(args.iter().any(|a| a == "--nocpp"), "{args:?}");
+        cfg.disable_preprocessor(false);
+        assert!(!default_args(&cfg).iter().any(|a| a == "--nocpp"));
+    }
+
+    #[test]
+    fn target_arch_from_triple() {
+        let cases = [
+            ("x86_64-unknown-linux-gnu", Some("x86-64")),
+            ("x86_64h-apple-darwin", Some("x86-64")),
+            ("i686-pc-windows-msvc", Some("x86")),
+            ("i586-unknown-linux-gnu", Some("x86")),
+            ("i386-apple-ios", Some("x86")),
+            ("aarch64-unknown-linux-gnu", Some("aarch64")),
+            ("aarch64_be-unknown-linux-gnu", Some("aarch64")),
+            ("arm64e-apple-darwin", Some("aarch64")),
+            ("arm64e-apple-ios", Some("aarch64")),
+            ("arm64ec-pc-windows-msvc", Some("aarch64")),
+            ("arm64_32-apple-watchos", Some("aarch64")),
+            ("arm-unknown-linux-gnueabihf", Some("arm")),
+            ("armv7-unknown-linux-gnueabihf", Some("arm")),
+            ("armv7s-apple-ios", Some("arm")),
+            ("thumbv7neon-linux-androideabi", Some("arm")),
+            ("riscv64gc-unknown-linux-gnu", None),
+            ("wasm32-unknown-unknown", None),
+        ];
+        for (target, arch) in cases {
+            assert_eq!(target_arch(target), arch, "{target}");
+        }
+    }
+
+    #[test]
+    fn cross_compile_args() {
+        let cases = [
+            ("armv7-unknown-linux-gnueabihf", "--arch=arm", true),
+            ("arm64ec-pc-windows-msvc", "--arch=aarch64", true),
+            ("aarch64-linux-android", "--arch=aarch64", true),
+            ("i586-unknown-linux-gnu", "--arch=x86", false),
+            ("x86_64-pc-windows-msvc", "--arch=x86-64", false),
+        ];
+        for (target, arch, neon) in cases {
+            let args = default_args(&config(target));
+            assert!(args.iter().any(|a| a == arch), "{target}: {args:?}");
+            assert_eq!(
+                args.iter().any(|a| a == "--target=neon-i32x4"),
+                neon,
+                "{target}: {args:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn source_dir_included_first() {
+        let src = env::temp_dir().join("kernels").join("foo.ispc");
+        let mut cfg = config("x86_64-unknown-linux-gnu");
+        cfg.include_path(env::temp_dir().join("include"));
+        let args = default_args(&cfg);
+        let job = with_env(&[], || {
+            cfg.ispc_job(&src, &args, "foo_ispc".to_string(), None, None, None)
+        });
+        let includes: Vec<String> = job
+            .cmd
+            .get_args()
+            .map(|a| a.to_string_lossy().into_owned())
+            .filter(|a| a.starts_with("-I"))
+            .collect();
+        let src_dir = format!("-I{}", env::temp_dir().join("kernels").display());
+        let user_dir = format!("-I{}", env::temp_dir().join("include").display());
+        assert_eq!(includes, [src_dir, user_dir]);
+    }
+
+    #[test]
+    fn fast_math_args() {
+        let mut cfg = config("x86_64-unknown-linux-gnu");
+        cfg.fast_math(true);
+        let args = default_args(&cfg);
+        assert!(args.iter().any(|a| a == "--opt=fast-math"), "{args:?}");
+        assert!(args.iter().any(|a| a == "--math-lib=fast"), "{args:?}");
+
+        cfg.fast_math(false);
+        let args = default_args(&cfg);
+        assert!(!args.iter().any(|a| a == "--opt=fast-math"), "{args:?}");
+        assert!(args.iter().any(|a| a == "--math-lib=default"), "{args:?}");
+    }
+
+    #[test]
+    fn suppress_perf_warnings_args() {
+        let mut cfg = config("x86_64-unknown-linux-gnu");
+        let before = default_args(&cfg);
+        cfg.suppress_perf_warnings(true);
+        let mut after = default_args(&cfg);
+        let added = after.iter().position(|a| a == "--wno-perf").unwrap();
+        after.remove(added);
+        assert_eq!(after, before);
+    }
+
+    #[test]
+    fn apple_arm64_args() {
+        for (target, os) in [
+            ("aarch64-apple-darwin", "--target-os=macos"),
+            ("aarch64-apple-ios", "--target-os=ios"),
+        ] {
+            let args = default_args(&config(target));
+            for flag in ["--arch=aarch64", "--target=neon-i32x4", os] {
+                assert!(args.iter().any(|a| a == flag), "{target}: {args:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn env_overrides_precedence() {
+        let mut cfg = config("x86_64-unknown-linux-gnu");
+        cfg.opt_level(2);
+        let env = [
+            ("ISPC_FLAGS", Some("-O1 --woff")),
+            ("ISPC_TARGET", Some("avx2-i32x8, sse4.2-i32x4")),
+        ];
+        let args = with_env(&env, || cfg.default_args());
+        // ISPC uses the last -O given, which must be the explicit setting
+        let opt = args.iter().rev().find(|a| a.starts_with("-O")).unwrap();
+        assert_eq!(opt, "-O2");
+        assert!(args.iter().any(|a| a == "--woff"), "{args:?}");
+        assert!(
+            args.iter().any(|a| a == "--target=avx2-i32x8,sse4.2-i32x4"),
+            "{args:?}"
+        );
+
+        cfg.target_isa(TargetISA::SSE2i32x4);
+        let args = with_env(&env, || cfg.default_args());
+        assert!(args.iter().any(|a| a == "--target=sse2-i32x4"), "{args:?}");
+    }
+
+    #[test]
+    fn env_opt_level_precedence() {
+        let mut cfg = config("x86_64-unknown-linux-gnu");
+        let env = [("ISPC_OPT_LEVEL", Some("1")), ("OPT_LEVEL", Some("3"))];
+        assert_eq!(with_env(&env, || cfg.get_opt_level()), 1);
+        cfg.opt_level(2);
+        assert_eq!(with_env(&env, || cfg.get_opt_level()), 2);
+    }
+
+    #[test]
+    fn dry_run_writes_nothing() {
+        let dir = env::temp_dir().join(format!("ispc-rs-dry-run-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut cfg = config("x86_64-unknown-linux-gnu");
+        cfg.out_dir(&dir)
+            .source_string("generated", "export void f() {}")
+            .dry_run(true);
+        with_env(&[], || cfg.compile("dry"));
+        let written: Vec<_> = fs::read_dir(&dir).unwrap().collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(written.is_empty());
+    }
+
+    #[test]
+    fn opt_level_from_cargo() {
+        let cfg = config("x86_64-unknown-linux-gnu");
+        for (opt, expected) in [("0", 0), ("2", 2), ("3", 3), ("s", 1), ("z", 1)] {
+            let level = with_env(&[("OPT_LEVEL", Some(opt))], || cfg.get_opt_level());
+            assert_eq!(level, expected, "OPT_LEVEL={opt}");
+        }
+        assert_eq!(with_env(&[], || cfg.get_opt_level()), 0);
+        let args = with_env(&[("OPT_LEVEL", Some("z"))], || cfg.default_args());
+        assert!(args.contains(&"-O1".to_string()), "{args:?}");
+    }
+
+    #[test]
+    fn jobs_from_cargo() {
+        let mut cfg = config("x86_64-unknown-linux-gnu");
+        assert_eq!(with_env(&[("NUM_JOBS", Some("3"))], || cfg.get_jobs()), 3);
+        assert_eq!(with_env(&[("NUM_JOBS", Some("0"))], || cfg.get_jobs()), 1);
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        assert_eq!(
+            with_env(&[("NUM_JOBS", Some("many"))], || cfg.get_jobs()),
+            cpus
+        );
+        assert_eq!(with_env(&[], || cfg.get_jobs()), cpus);
+        cfg.jobs(2);
+        assert_eq!(with_env(&[("NUM_JOBS", Some("3"))], || cfg.get_jobs()), 2);
+    }
+
+    #[test]
+    fn codegen_toggle_args() {
+        let mut cfg = config("x86_64-unknown-linux-gnu");
+        cfg.disable_fma(true)
+            .force_aligned_memory(true)
+            .disable_loop_unroll(true);
+        let args = default_args(&cfg);
+        for opt in [
+            "--opt=disable-fma",
+            "--opt=force-aligned-memory",
+            "--opt=disable-loop-unroll",
+        ] {
+            assert!(args.iter().any(|a| a == opt), "{opt} in {args:?}");
+        }
+
+        cfg.force_aligned_memory(false);
+        let args = default_args(&cfg);
+        assert!(args.iter().any(|a| a == "--opt=disable-fma"), "{args:?}");
+        assert!(
+            !args.iter().any(|a| a == "--opt=force-aligned-memory"),
+            "{args:?}"
+        );
+    }
+
+    #[test]
+    fn target_isa_from_rust_features() {
+        let isa_for = |version: &str, features: Option<&str>| {
+            let mut cfg = Config::with_version_string(&format!(
+                "Intel(r) Implicit SPMD Program Compiler (Intel(r) ISPC), {version} (build commit)"
+            ));
+            with_env(&[("CARGO_CFG_TARGET_FEATURE", features)], || {
+                cfg.target_from_rust_cfg();
+            });
+            cfg.target_isa
+        };
+        let avx2vnni = Some("sse2,sse4.1,sse4.2,avx,avx2,fma,avxvnni");
+        assert!(isa_for("1.23.0", avx2vnni) == Some(vec![TargetISA::AVX2VNNIi32x8]));
+        // avx2vnni targets need ISPC 1.21, so older versions fall back to avx2
+        assert!(isa_for("1.20.0", avx2vnni) == Some(vec![TargetISA::AVX2i32x8]));
+        assert!(isa_for("1.23.0", Some("sse2,avx2")) == Some(vec![TargetISA::SSE2i32x4]));
+        assert!(isa_for("1.23.0", Some("neon")) == Some(vec![TargetISA::Neoni32x4]));
+        assert!(isa_for("1.23.0", Some("crt-static")).is_none());
+        assert!(isa_for("1.23.0", None).is_none());
+    }
+
+    #[test]
+    fn link_search_dir_resolves_relative_paths() {
+        let dir = env::temp_dir().join(format!("ispc-rs-search-{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        let resolved = link_search_dir(&dir.join("lib").join("..").join("lib"));
+        let canonical = link_search_dir(&dir.join("lib"));
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(resolved, canonical);
+        assert!(resolved.is_absolute());
+        assert!(!resolved.components().any(|c| c == Component::ParentDir));
+
+        // A directory which doesn't exist yet can't be resolved, but is still absolute
+        let missing = Path::new("ispc-rs-missing-dir");
+        assert_eq!(link_search_dir(missing), absolute_path(missing));
+        assert!(link_search_dir(missing).is_absolute());
+    }
+
+    #[test]
+    fn out_dir_included_after_user_paths() {
+        let out_dir = env::temp_dir().join("out");
+        let mut cfg = config("x86_64-unknown-linux-gnu");
+        cfg.include_path(env::temp_dir().join("include"));
+        let args = with_env(&[("OUT_DIR", out_dir.to_str())], || cfg.default_args());
+        let includes: Vec<&String> = args.iter().filter(|a| a.starts_with("-I")).collect();
+        let user_dir = format!("-I{}", env::temp_dir().join("include").display());
+        let out_dir = format!("-I{}", out_dir.display());
+        assert_eq!(includes, [&user_dir, &out_dir]);
+
+        let args = default_args(&cfg);
+        assert!(args.iter().filter(|a| a.starts_with("-I")).eq([&user_dir]));
+    }
+
+    #[test]
+    fn fast_masked_vload_args() {
+        let mut cfg = config("x86_64-unknown-linux-gnu");
+        assert!(!default_args(&cfg)
+            .iter()
+            .any(|a| a == "--opt=fast-masked-vload"));
+        cfg.fast_masked_vload(true);
+        let args = default_args(&cfg);
+        assert!(
+            args.iter().any(|a| a == "--opt=fast-masked-vload"),
+            "{args:?}"
+        );
+        cfg.fast_masked_vload(false);
+        assert!(!default_args(&cfg)
+            .iter()
+            .any(|a| a == "--opt=fast-masked-vload"));
+    }
+
+    #[test]
+    fn dwarf_version_args() {
+        let mut cfg = config("x86_64-unknown-linux-gnu");
+        cfg.dwarf_version(4);
+        let args = default_args(&cfg);
+        assert!(!args.iter().any(|a| a == "-g"), "{args:?}");
+        assert!(
+            !args.iter().any(|a| a.starts_with("--dwarf-version")),
+            "{args:?}"
+        );
+
+        cfg.debug(true);
+        let args = default_args(&cfg);
+        assert!(args.iter().any(|a| a == "-g"), "{args:?}");
+        assert!(args.iter().any(|a| a == "--dwarf-version=4"), "{args:?}");
+
+        cfg.dwarf_version(2);
+        let args = default_args(&cfg);
+        let dwarf: Vec<&String> = args.iter().filter(|a| a.starts_with("--dwarf")).collect();
+        assert_eq!(dwarf, ["--dwarf-version=2"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn merge_libraries_prefixes_objects() {
+        let dir = env::temp_dir().join(format!("ispc-rs-merge-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let libs: Vec<PathBuf> = ["foo", "bar"]
+            .iter()
+            .map(|name| {
+                let obj = dir.join("kernel.o");
+                fs::write(&obj, name).unwrap();
+                let lib = dir.join(format!("lib{name}.a"));
+                let status = Command::new("ar").arg("crs").arg(&lib).arg(&obj).status();
+                assert!(status.unwrap().success());
+                lib
+            })
+            .collect();
+        let merged = with_env(&[("OUT_DIR", dir.to_str())], || {
+            merge_libraries("merged", &libs)
+        });
+        let members = Command::new("ar").arg("t").arg(&merged).output().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let members = String::from_utf8_lossy(&members.stdout);
+        assert_eq!(
+            members.lines().collect::<Vec<_>>(),
+            ["0_kernel.o", "1_kernel.o"]
+        );
+    }
+
+    #[test]
+    fn generated_sources_use_final_out_dir() {
+        let mut cfg = Config::with_version_string(
+            "Intel(r) Implicit SPMD Program Compiler (Intel(r) ISPC), 1.23.0 (build commit)",
+        );
+        let out_dir = env::temp_dir().join("generated");
+        cfg.file("kernels/foo.ispc")
+            .source_string("bar", "export void bar() {}");
+        cfg.out_dir(&out_dir);
+        let files = with_env(&[], || cfg.source_files());
+        assert_eq!(
+            files,
+            [PathBuf::from("kernels/foo.ispc"), out_dir.join("bar.ispc")]
+        );
+    }
+}
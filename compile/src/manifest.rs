@@ -0,0 +1,74 @@
+//! Writes the JSON manifest describing the functions exported from a compiled
+//! ISPC library, for tools which want to generate wrappers or check call sites.
+
+use std::fmt::Write;
+use std::path::Path;
+
+use crate::header::ExportedFunction;
+
+/// Build the manifest for `lib` from the functions exported by each ISPC source file.
+///
+/// The manifest has the form:
+///
+/// ```json
+/// {
+///   "library": "simple",
+///   "functions": [
+///     {
+///       "name": "add_lists",
+///       "source": "src/simple.ispc",
+///       "return_type": "void",
+///       "params": [{ "name": "a", "type": "float *" }]
+///     }
+///   ]
+/// }
+/// ```
+pub fn to_json(lib: &str, exports: &[(&Path, Vec<ExportedFunction>)]) -> String {
+    let mut functions = Vec::new();
+    for (src, funcs) in exports {
+        for f in funcs {
+            let params = f
+                .params
+                .iter()
+                .map(|(ty, name)| {
+                    format!(
+                        "{{ \"name\": {}, \"type\": {} }}",
+                        json_string(name),
+                        json_string(ty)
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            functions.push(format!(
+                "    {{\n      \"name\": {},\n      \"source\": {},\n      \
+                 \"return_type\": {},\n      \"params\": [{}]\n    }}",
+                json_string(&f.name),
+                json_string(&src.display().to_string()),
+                json_string(&f.return_type),
+                params
+            ));
+        }
+    }
+    format!(
+        "{{\n  \"library\": {},\n  \"functions\": [\n{}\n  ]\n}}\n",
+        json_string(lib),
+        functions.join(",\n")
+    )
+}
+
+/// Quote and escape `s` as a JSON string.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -2,12 +2,15 @@
 //! located here for convience and clutter reduction.
 
 /// Different math libraries that ISPC can use for computations.
+#[derive(Clone)]
 pub enum MathLib {
     /// Use ispc's built-in math functions (the default).
     ISPCDefault,
     /// Use high-performance but lower-accuracy math functions.
     Fast,
-    /// Use the Intel(r) SVML math libraries.
+    /// Use the Intel(r) SVML math libraries. This requires the SVML runtime
+    /// libraries from the Intel(r) oneAPI compilers, which will be linked
+    /// automatically. Use `Config::svml_path` to specify where to find them.
     SVML,
     /// Use the system's math library (**may be quite slow**).
     System,
@@ -24,7 +27,54 @@ impl std::fmt::Display for MathLib {
     }
 }
 
+/// The kind of library to link the compiled ISPC code into.
+#[derive(Clone)]
+pub enum LibraryKind {
+    /// A static library which is linked into the crate (the default).
+    Static,
+    /// A shared library. Cargo is told to link against it dynamically, so it
+    /// must be found by the loader when the program is run.
+    Shared,
+}
+
+/// How to link an additional library added with `Config::link_lib`.
+#[derive(Clone)]
+pub enum LinkKind {
+    /// Let rustc pick the kind of library to link.
+    Default,
+    /// Link a static library.
+    Static,
+    /// Link a dynamic library.
+    Dylib,
+    /// Link a macOS framework.
+    Framework,
+}
+
+impl std::fmt::Display for LinkKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            LinkKind::Default => Ok(()),
+            LinkKind::Static => write!(f, "static="),
+            LinkKind::Dylib => write!(f, "dylib="),
+            LinkKind::Framework => write!(f, "framework="),
+        }
+    }
+}
+
+/// Control whether ISPC colors its diagnostics.
+#[derive(Clone)]
+pub enum ColorChoice {
+    /// Follow Cargo's `CARGO_TERM_COLOR` setting if it's set, otherwise leave
+    /// it up to ISPC (the default).
+    Auto,
+    /// Always color the output.
+    Always,
+    /// Never color the output.
+    Never,
+}
+
 /// Select the target CPU architecture
+#[derive(Clone)]
 pub enum Architecture {
     Arm,
     Aarch64,
@@ -48,6 +98,7 @@ impl std::fmt::Display for Architecture {
 /// Select 32 or 64 bit addressing to be used by ISPC. Note: 32-bit
 /// addressing calculations are done by default, even on 64 bit target
 /// architectures.
+#[derive(Clone)]
 pub enum Addressing {
     /// Select 32 bit addressing calculations.
     A32,
@@ -64,8 +115,89 @@ impl std::fmt::Display for Addressing {
     }
 }
 
+/// Additional kinds of output ISPC can write for inspecting the generated code,
+/// alongside the object files and headers needed to link the library.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum EmitKind {
+    /// Assembly, written to a `.s` file.
+    Asm,
+    /// LLVM bitcode, written to a `.bc` file.
+    Llvm,
+    /// Textual LLVM IR, written to a `.ll` file.
+    LlvmText,
+}
+
+impl EmitKind {
+    /// The extension of the files this kind of output is written to
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            EmitKind::Asm => "s",
+            EmitKind::Llvm => "bc",
+            EmitKind::LlvmText => "ll",
+        }
+    }
+}
+
+impl std::fmt::Display for EmitKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            EmitKind::Asm => write!(f, "--emit-asm"),
+            EmitKind::Llvm => write!(f, "--emit-llvm"),
+            EmitKind::LlvmText => write!(f, "--emit-llvm-text"),
+        }
+    }
+}
+
+/// The syntax used for x86 assembly written with `EmitKind::Asm`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum AsmSyntax {
+    /// Intel syntax, e.g. `mov eax, 1`.
+    Intel,
+    /// AT&T syntax, e.g. `movl $1, %eax`.
+    Att,
+}
+
+impl std::fmt::Display for AsmSyntax {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            AsmSyntax::Intel => write!(f, "--x86-asm-syntax=intel"),
+            AsmSyntax::Att => write!(f, "--x86-asm-syntax=att"),
+        }
+    }
+}
+
+/// Select the format of the debug information emitted when debug symbols are
+/// generated.
+#[derive(Clone, Eq, PartialEq)]
+pub enum DebugFormat {
+    /// Emit DWARF version 2 debug information.
+    Dwarf2,
+    /// Emit DWARF version 3 debug information.
+    Dwarf3,
+    /// Emit DWARF version 4 debug information.
+    Dwarf4,
+    /// Emit DWARF version 5 debug information.
+    Dwarf5,
+    /// Emit CodeView debug information for PDB files, which is what ISPC
+    /// produces for Windows targets.
+    CodeView,
+}
+
+impl std::fmt::Display for DebugFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            DebugFormat::Dwarf2 => write!(f, "--dwarf-version=2"),
+            DebugFormat::Dwarf3 => write!(f, "--dwarf-version=3"),
+            DebugFormat::Dwarf4 => write!(f, "--dwarf-version=4"),
+            DebugFormat::Dwarf5 => write!(f, "--dwarf-version=5"),
+            // CodeView is selected by -g alone on Windows targets
+            DebugFormat::CodeView => Ok(()),
+        }
+    }
+}
+
 /// ISPC target CPU ISA options. If none is set, ISPC will target the machine being compiled on.
-#[derive(Eq, PartialEq)]
+#[derive(Clone, Eq, PartialEq)]
 pub enum CPU {
     Generic,
     X8664,
@@ -170,7 +302,7 @@ impl std::fmt::Display for CPU {
 }
 
 /// ISPC optimization options.
-#[derive(Ord, PartialOrd, Eq, PartialEq)]
+#[derive(Clone, Ord, PartialOrd, Eq, PartialEq)]
 pub enum OptimizationOpt {
     /// Remove assertion statements from final code.
     DisableAssertions,
@@ -280,7 +412,9 @@ pub enum TargetISA {
     AVX512SPRx32,
     AVX512SPRx64,
     // Neon targets
+    /// Only supported on 32-bit ARM
     Neoni8x16,
+    /// Only supported on 32-bit ARM
     Neoni16x8,
     Neoni32x4,
     Neoni32x8,
@@ -457,8 +591,81 @@ impl std::fmt::Display for TargetISA {
     }
 }
 
+impl std::str::FromStr for TargetISA {
+    type Err = String;
+
+    /// Parse a target ISA from the name ISPC uses for it on the command line,
+    /// e.g. `avx2-i32x8`.
+    #[allow(deprecated)]
+    fn from_str(s: &str) -> Result<TargetISA, String> {
+        match s {
+            "host" => Ok(TargetISA::Host),
+            "sse2-i32x4" => Ok(TargetISA::SSE2i32x4),
+            "sse2-i32x8" => Ok(TargetISA::SSE2i32x8),
+            "sse4.1-i8x16" => Ok(TargetISA::SSE41i8x16),
+            "sse4.1-i16x8" => Ok(TargetISA::SSE41i16x8),
+            "sse4.1-i32x4" => Ok(TargetISA::SSE41i32x4),
+            "sse4.1-i32x8" => Ok(TargetISA::SSE41i32x8),
+            "sse4.2-i8x16" => Ok(TargetISA::SSE42i8x16),
+            "sse4.2-i16x8" => Ok(TargetISA::SSE42i16x8),
+            "sse4.2-i32x4" => Ok(TargetISA::SSE42i32x4),
+            "sse4.2-i32x8" => Ok(TargetISA::SSE42i32x8),
+            "sse4-i8x16" => Ok(TargetISA::SSE4i8x16),
+            "sse4-i16x8" => Ok(TargetISA::SSE4i16x8),
+            "sse4-i32x4" => Ok(TargetISA::SSE4i32x4),
+            "sse4-i32x8" => Ok(TargetISA::SSE4i32x8),
+            "avx1-i32x4" => Ok(TargetISA::AVX1i32x4),
+            "avx1-i32x8" => Ok(TargetISA::AVX1i32x8),
+            "avx1-i32x16" => Ok(TargetISA::AVX1i32x16),
+            "avx1-i64x4" => Ok(TargetISA::AVX1i64x4),
+            "avx2-i32x8" => Ok(TargetISA::AVX2i32x8),
+            "avx2-i32x16" => Ok(TargetISA::AVX2i32x16),
+            "avx2-i64x4" => Ok(TargetISA::AVX2i64x4),
+            "avx2-i8x32" => Ok(TargetISA::AVX2i8x32),
+            "avx2-i16x16" => Ok(TargetISA::AVX2i16x16),
+            "avx2-i32x4" => Ok(TargetISA::AVX2i32x4),
+            "avx2vnni-i32x4" => Ok(TargetISA::AVX2VNNIi32x4),
+            "avx2vnni-i32x8" => Ok(TargetISA::AVX2VNNIi32x8),
+            "avx2vnni-i32x16" => Ok(TargetISA::AVX2VNNIi32x16),
+            "avx512knl-i32x16" => Ok(TargetISA::AVX512KNLi32x16),
+            "avx512skx-i32x16" => Ok(TargetISA::AVX512SKXi32x16),
+            "avx512skx-i32x8" => Ok(TargetISA::AVX512SKXi32x8),
+            "avx512knl-x16" => Ok(TargetISA::AVX512KNLx16),
+            "avx512skx-x4" => Ok(TargetISA::AVX512SKXx4),
+            "avx512skx-x8" => Ok(TargetISA::AVX512SKXx8),
+            "avx512skx-x16" => Ok(TargetISA::AVX512SKXx16),
+            "avx512skx-x32" => Ok(TargetISA::AVX512SKXx32),
+            "avx512skx-x64" => Ok(TargetISA::AVX512SKXx64),
+            "avx512icl-x4" => Ok(TargetISA::AVX512ICLx4),
+            "avx512icl-x8" => Ok(TargetISA::AVX512ICLx8),
+            "avx512icl-x16" => Ok(TargetISA::AVX512ICLx16),
+            "avx512icl-x32" => Ok(TargetISA::AVX512ICLx32),
+            "avx512icl-x64" => Ok(TargetISA::AVX512ICLx64),
+            "avx512spr-x4" => Ok(TargetISA::AVX512SPRx4),
+            "avx512spr-x8" => Ok(TargetISA::AVX512SPRx8),
+            "avx512spr-x16" => Ok(TargetISA::AVX512SPRx16),
+            "avx512spr-x32" => Ok(TargetISA::AVX512SPRx32),
+            "avx512spr-x64" => Ok(TargetISA::AVX512SPRx64),
+            "neon-i8x16" => Ok(TargetISA::Neoni8x16),
+            "neon-i16x8" => Ok(TargetISA::Neoni16x8),
+            "neon-i32x4" => Ok(TargetISA::Neoni32x4),
+            "neon-i32x8" => Ok(TargetISA::Neoni32x8),
+            "gen9-x8" => Ok(TargetISA::GEN9x8),
+            "gen9-x16" => Ok(TargetISA::GEN9x16),
+            "xelp-x8" => Ok(TargetISA::XELPx8),
+            "xelp-x16" => Ok(TargetISA::XELPx16),
+            "xehpg-x8" => Ok(TargetISA::XEHPGx8),
+            "xehpg-x16" => Ok(TargetISA::XEHPGx16),
+            "xehpc-x16" => Ok(TargetISA::XEHPCx16),
+            "xehpc-x32" => Ok(TargetISA::XEHPCx32),
+            _ => Err(format!("Unknown ISPC target ISA '{s}'")),
+        }
+    }
+}
+
 /// Target instruction sets and vector widths available to specialize for. The
 /// default if none is set will be the host CPU's ISA and vector width.
+#[derive(Clone)]
 pub enum TargetOS {
     Windows,
     Ps4,
@@ -493,3 +700,28 @@ impl std::fmt::Display for TargetOS {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_target_isa() {
+        let isas = [
+            TargetISA::Host,
+            TargetISA::SSE2i32x4,
+            TargetISA::SSE42i32x4,
+            TargetISA::AVX1i32x8,
+            TargetISA::AVX2i32x8,
+            TargetISA::AVX2VNNIi32x8,
+            TargetISA::AVX512SKXx16,
+            TargetISA::Neoni32x4,
+        ];
+        for isa in isas {
+            assert!(isa.to_string().parse::<TargetISA>() == Ok(isa), "{isa}");
+        }
+        assert!("avx2-i32x8".parse::<TargetISA>() == Ok(TargetISA::AVX2i32x8));
+        assert!("avx3-i32x8".parse::<TargetISA>().is_err());
+        assert!("".parse::<TargetISA>().is_err());
+    }
+}
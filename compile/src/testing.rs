@@ -0,0 +1,225 @@
+//! Helpers for calling ISPC kernels from tests without going through a build
+//! script and `ispc_module!`. The kernels are compiled into a shared library in
+//! a temporary directory, which is then loaded with `dlopen` so the exported
+//! functions can be looked up by name.
+//!
+//! Tests written in ISPC itself can also be compiled into an executable and run
+//! with `run`, see it for the entry point the kernels must export.
+//!
+//! This module requires the `testing` feature and is only available on Unix.
+//! Bindings aren't generated for the library, so libclang isn't needed.
+//! Kernels which launch tasks can't be loaded this way, since the ISPC task
+//! runtime functions provided by `ispc_rt` aren't exported from test executables.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use ispc_compile::{testing, Config};
+//!
+//! let lib = testing::load(Config::new().file("src/simple.ispc"), "simple");
+//! let add_lists: extern "C" fn(*const f32, *const f32, *mut f32, i32) =
+//!     unsafe { lib.get("add_lists") }.expect("add_lists isn't exported");
+//! ```
+
+use std::env;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, ExitStatus};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{Config, LibraryKind};
+
+/// A compiled ISPC library loaded with `dlopen`, which is closed and has its
+/// temporary directory removed when dropped.
+pub struct Library {
+    handle: *mut libc::c_void,
+    path: PathBuf,
+    dir: PathBuf,
+}
+
+impl Library {
+    /// Get the path to the shared library which was loaded
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    /// Look up the function `name` exported from the library, returns None if
+    /// the library doesn't export it.
+    ///
+    /// # Safety
+    /// `T` must be an `extern "C"` function pointer type matching the signature
+    /// of the exported ISPC function, and it must not be called after the
+    /// library is dropped.
+    pub unsafe fn get<T: Copy>(&self, name: &str) -> Option<T> {
+        assert_eq!(
+            mem::size_of::<T>(),
+            mem::size_of::<*mut libc::c_void>(),
+            "Exported functions must be looked up as function pointers"
+        );
+        let name = CString::new(name).ok()?;
+        let sym = libc::dlsym(self.handle, name.as_ptr());
+        if sym.is_null() {
+            None
+        } else {
+            Some(mem::transmute_copy(&sym))
+        }
+    }
+}
+
+impl Drop for Library {
+    fn drop(&mut self) {
+        unsafe { libc::dlclose(self.handle) };
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Compile the ISPC files added to `cfg` into a shared library named `lib` in
+/// a new temporary directory and load it. Settings Cargo normally provides to
+/// build scripts, like the target and optimization level, default to building
+/// optimized code for the host if they aren't set on `cfg` or in the environment.
+///
+/// Like `Config::compile`, this will exit the process if compilation fails.
+pub fn load(cfg: &mut Config, lib: &str) -> Library {
+    let dir = compile(cfg, lib, LibraryKind::Shared);
+
+    let path = cfg.library_path(&(lib.to_owned() + &cfg.get_target()));
+    let c_path = CString::new(path.as_os_str().as_bytes()).expect("Library path can't contain NUL");
+    let handle = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+    if handle.is_null() {
+        let err = unsafe { CStr::from_ptr(libc::dlerror()) };
+        let _ = fs::remove_dir_all(&dir);
+        panic!(
+            "Failed to load ISPC library {}: {}",
+            path.display(),
+            err.to_string_lossy()
+        );
+    }
+    Library { handle, path, dir }
+}
+
+/// The C `main` of the executables built by `run`, which calls the test's entry
+/// point. The ISPC task runtime functions are implemented here too, running the
+/// tasks serially as they're launched, since `ispc_rt` isn't linked in.
+const TEST_MAIN: &str = r#"#include <stdint.h>
+#include <stdlib.h>
+
+typedef void (*ispc_task_fn)(void *data, int thread_idx, int thread_cnt, int task_idx,
+                             int task_cnt, int task_idx0, int task_idx1, int task_idx2,
+                             int task_cnt0, int task_cnt1, int task_cnt2);
+
+/* The parameter blocks allocated for the tasks launched under a handle */
+struct allocation {
+    void *ptr;
+    struct allocation *next;
+};
+
+void *ISPCAlloc(void **handle, int64_t size, int32_t align) {
+    struct allocation *a = malloc(sizeof(struct allocation));
+    /* aligned_alloc needs the size to be a multiple of the alignment */
+    size_t alignment = align < (int32_t)sizeof(void *) ? sizeof(void *) : (size_t)align;
+    size_t padded = ((size_t)size + alignment - 1) / alignment * alignment;
+    a->ptr = aligned_alloc(alignment, padded ? padded : alignment);
+    a->next = *handle;
+    *handle = a;
+    return a->ptr;
+}
+
+void ISPCLaunch(void **handle, void *f, void *data, int cnt0, int cnt1, int cnt2) {
+    (void)handle;
+    int total = cnt0 * cnt1 * cnt2;
+    for (int i = 0; i < total; ++i) {
+        ((ispc_task_fn)f)(data, 0, 1, i, total, i % cnt0, (i / cnt0) % cnt1,
+                          i / (cnt0 * cnt1), cnt0, cnt1, cnt2);
+    }
+}
+
+void ISPCSync(void *handle) {
+    struct allocation *a = handle;
+    while (a) {
+        struct allocation *next = a->next;
+        free(a->ptr);
+        free(a);
+        a = next;
+    }
+}
+
+extern int32_t test_main(void);
+
+int main(void) {
+    return test_main();
+}
+"#;
+
+/// Compile the ISPC files added to `cfg` into an executable and run it, returning its
+/// exit status, to drive tests written in ISPC from `cargo test`. One of the files
+/// must export the test's entry point, which returns the exit status:
+///
+/// ```ispc
+/// export uniform int test_main() {
+///     return all_checks_passed() ? 0 : 1;
+/// }
+/// ```
+///
+/// The kernels are compiled into a static library named `lib` in a new temporary
+/// directory like with `load`, and linked with a C `main` calling `test_main`.
+/// The directory is removed once the executable has run.
+/// Kernels may launch tasks, which run serially on the calling thread. The
+/// executable's output is passed through to the test's.
+///
+/// Like `Config::compile`, this will exit the process if compilation fails, and
+/// it panics if the executable can't be linked or run.
+pub fn run(cfg: &mut Config, lib: &str) -> ExitStatus {
+    let dir = compile(cfg, lib, LibraryKind::Static);
+    let main = dir.join("ispc_test_main.c");
+    fs::write(&main, TEST_MAIN).expect("Failed to write the ISPC test's main");
+    let exe = dir.join(lib);
+    let mut link = cfg.cc_build().get_compiler().to_command();
+    link.arg(&main)
+        .arg(cfg.library_path(&(lib.to_owned() + &cfg.get_target())))
+        .arg("-o")
+        .arg(&exe);
+    // ISPC's math functions call into libm, which is part of libSystem on macOS
+    if !cfg.get_target().contains("apple") {
+        link.arg("-lm");
+    }
+    let status = link
+        .status()
+        .unwrap_or_else(|e| panic!("Failed to run the linker for the ISPC test {lib}: {e}"));
+    if !status.success() {
+        panic!("Failed to link the ISPC test {lib}");
+    }
+    let status = Command::new(&exe)
+        .status()
+        .unwrap_or_else(|e| panic!("Failed to run the ISPC test {}: {}", exe.display(), e));
+    let _ = fs::remove_dir_all(&dir);
+    status
+}
+
+/// Compile the ISPC files added to `cfg` into a library of `kind` named `lib` in a
+/// new temporary directory, which is returned
+fn compile(cfg: &mut Config, lib: &str, kind: LibraryKind) -> PathBuf {
+    static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+    let dir = env::temp_dir().join(format!(
+        "ispc-rs-{}-{}-{}",
+        lib,
+        process::id(),
+        NEXT_DIR.fetch_add(1, Ordering::SeqCst)
+    ));
+    fs::create_dir_all(&dir).expect("Failed to create temporary directory to compile ISPC into");
+
+    cfg.out_dir(&dir)
+        .library_kind(kind)
+        .generate_bindings(false)
+        .cargo_metadata(false);
+    cfg.build_dir = Some(dir.clone());
+    if cfg.debug.is_none() && env::var_os("DEBUG").is_none() {
+        cfg.debug(false);
+    }
+    if cfg.opt_level.is_none() && env::var_os("OPT_LEVEL").is_none() {
+        cfg.opt_level(2);
+    }
+    cfg.compile(lib);
+    dir
+}
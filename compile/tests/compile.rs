@@ -0,0 +1,137 @@
+//! Tests compiling ISPC kernels through `Config`, which are skipped if the ISPC
+//! compiler isn't installed.
+
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::rc::Rc;
+
+use ispc_compile::Config;
+
+/// Check if ISPC is installed, printing that the test is skipped if it isn't
+fn ispc_installed(test: &str) -> bool {
+    let installed = ispc_compile::ispc_version().is_some();
+    if !installed {
+        eprintln!("Skipping {test}, ISPC isn't installed");
+    }
+    installed
+}
+
+/// Get the path to the test kernel `name`
+fn kernel(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("kernels")
+        .join(name)
+}
+
+/// Create an empty output directory for the test `test`
+fn out_dir(test: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("ispc-rs-{test}-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn compile_twice() {
+    if !ispc_installed("compile_twice") {
+        return;
+    }
+    let dir = out_dir("compile_twice");
+    let library = Rc::new(RefCell::new(None));
+    let output = Rc::clone(&library);
+    let mut cfg = Config::new();
+    cfg.file(kernel("add.ispc"))
+        .file(kernel("scale.ispc"))
+        .out_dir(&dir)
+        .cargo_metadata(false)
+        .generate_bindings(false)
+        .after_compile(move |o| *output.borrow_mut() = Some(o.library.clone()));
+
+    cfg.compile("twice");
+    let objects = cfg.objects();
+    let library = library.borrow().clone().unwrap();
+    let size = fs::metadata(&library).unwrap().len();
+
+    cfg.compile("twice");
+    assert_eq!(cfg.objects(), objects);
+    assert_eq!(fs::metadata(&library).unwrap().len(), size);
+    #[cfg(unix)]
+    {
+        let members = process::Command::new("ar")
+            .arg("t")
+            .arg(&library)
+            .output()
+            .unwrap();
+        let members = String::from_utf8_lossy(&members.stdout);
+        // Apple's ar lists the symbol table as a member
+        let count = members
+            .lines()
+            .filter(|m| !m.starts_with("__.SYMDEF"))
+            .count();
+        assert_eq!(count, objects.len(), "{members}");
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn llvm_intrinsics() {
+    if !ispc_installed("llvm_intrinsics") {
+        return;
+    }
+    let dir = out_dir("llvm_intrinsics");
+    Config::new()
+        .file(kernel("intrinsic.ispc"))
+        .enable_llvm_intrinsics()
+        .out_dir(&dir)
+        .cargo_metadata(false)
+        .generate_bindings(false)
+        .compile("intrinsic");
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn include_sibling_header() {
+    if !ispc_installed("include_sibling_header") {
+        return;
+    }
+    let dir = out_dir("include_sibling_header");
+    Config::new()
+        .file(kernel("sibling.ispc"))
+        .out_dir(&dir)
+        .cargo_metadata(false)
+        .generate_bindings(false)
+        .compile("sibling");
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn export_global_table() {
+    if !ispc_installed("export_global_table") {
+        return;
+    }
+    let dir = out_dir("export_global_table");
+    let bindings = Rc::new(RefCell::new(None));
+    let output = Rc::clone(&bindings);
+    Config::new()
+        .file(kernel("global_table.ispc"))
+        .export_global("float table[256]")
+        .out_dir(&dir)
+        .cargo_metadata(false)
+        .after_compile(move |o| *output.borrow_mut() = o.bindings.clone())
+        .compile("global_table");
+    let bindings = bindings
+        .borrow()
+        .clone()
+        .expect("bindings weren't generated");
+    let bindings = fs::read_to_string(bindings).unwrap();
+    assert!(
+        bindings.contains("pub static mut table: [f32; 256"),
+        "{bindings}"
+    );
+    assert!(bindings.contains("pub fn lookup("), "{bindings}");
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -9,4 +9,11 @@ fn main() {
         multi_file::fcn_a();
         multi_file::fcn_b();
     }
+    // Point is exported by both files, but only one Rust definition is generated
+    let mut p = multi_file::Point { x: 1.0, y: 2.0 };
+    unsafe {
+        multi_file::scale_point_a(&mut p, 2.0);
+        multi_file::offset_point_b(&mut p, 1.0);
+    }
+    println!("Point: ({}, {})", p.x, p.y);
 }
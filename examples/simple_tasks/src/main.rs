@@ -7,4 +7,26 @@ fn main() {
     unsafe {
         simple_tasks::run_tasks();
     }
+    // Every leaf task launched from the nested tasks should run exactly once
+    let mut counts = vec![0; 256];
+    unsafe {
+        simple_tasks::run_nested_tasks(counts.as_mut_ptr(), (counts.len() / 4) as i32);
+    }
+    assert!(counts.iter().all(|&c| c == 1));
+    println!("Nested launches completed");
+
+    // Each task in a 2D launch should get the x and y index of its position in the grid
+    let (width, height) = (5, 3);
+    let mut indices = vec![-1; 2 * width * height];
+    unsafe {
+        simple_tasks::run_2d_tasks(indices.as_mut_ptr(), width as i32, height as i32);
+    }
+    for y in 0..height {
+        for x in 0..width {
+            let t = x + y * width;
+            assert_eq!(indices[2 * t], x as i32);
+            assert_eq!(indices[2 * t + 1], y as i32);
+        }
+    }
+    println!("2D launch task indices are correct");
 }
@@ -8,7 +8,6 @@ use std::cell::RefCell;
 use std::sync::atomic::{self, AtomicUsize};
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread::{self, JoinHandle};
-use std::time::Duration;
 
 use crate::task::{Context, ISPCTaskFn};
 
@@ -96,7 +95,8 @@ pub struct Parallel {
     context_list: RwLock<Vec<Arc<Context>>>,
     next_context_id: AtomicUsize,
     threads: Mutex<Vec<JoinHandle<()>>>,
-    chunk_size: usize,
+    /// The number of threads running tasks, including the main thread
+    total_threads: usize,
 }
 
 impl Parallel {
@@ -109,27 +109,31 @@ impl Parallel {
     /// `oversubscribe * num_cpus` threads to run tasks.
     pub fn oversubscribed(oversubscribe: f32) -> Arc<Parallel> {
         assert!(oversubscribe >= 1.0);
+        Parallel::with_threads((oversubscribe * num_cpus::get() as f32) as usize)
+    }
+    /// Create a parallel task execution environment that will use `num_threads` worker
+    /// threads to run tasks. The thread calling into ISPC will also help run tasks while
+    /// it waits on them in `sync`.
+    pub fn with_threads(num_threads: usize) -> Arc<Parallel> {
         let par = Arc::new(Parallel {
             context_list: RwLock::new(Vec::new()),
             next_context_id: AtomicUsize::new(0),
             threads: Mutex::new(Vec::new()),
-            chunk_size: 8,
+            total_threads: num_threads + 1,
         });
         {
             let mut threads = par.threads.lock().unwrap();
-            let num_threads = (oversubscribe * num_cpus::get() as f32) as usize;
-            let chunk_size = par.chunk_size;
             for i in 0..num_threads {
                 let task_sys = Arc::clone(&par);
                 // Note that the spawned thread ids start at 1 since the main thread is 0
                 threads.push(thread::spawn(move || {
-                    Parallel::worker_thread(task_sys, i + 1, num_threads + 1, chunk_size)
+                    Parallel::worker_thread(task_sys, i + 1, num_threads + 1)
                 }));
             }
         }
         par
     }
-    /// Return a context that has remaining tasks left to be executed by a thread, returns None
+    /// Return a context that has tasks left to be given out to a thread, returns None
     /// if no contexts have remaining tasks.
     ///
     /// Note that due to threading issues you shouldn't assume the context returned actually has
@@ -139,25 +143,28 @@ impl Parallel {
             .read()
             .unwrap()
             .iter()
-            .find(|c| !c.current_tasks_done())
+            .find(|c| c.has_tasks())
             .cloned()
     }
-    fn worker_thread(
-        task_sys: Arc<Parallel>,
-        thread: usize,
-        total_threads: usize,
-        chunk_size: usize,
-    ) {
+    /// Steal a chunk of tasks from any context with tasks remaining and run it,
+    /// returns false if there was no work to steal.
+    fn steal_chunk(&self, thread: usize, total_threads: usize) -> bool {
+        while let Some(c) = self.get_context() {
+            if c.execute_chunk(
+                crate::task_chunk_size(),
+                thread as i32,
+                total_threads as i32,
+            ) {
+                return true;
+            }
+        }
+        false
+    }
+    fn worker_thread(task_sys: Arc<Parallel>, thread: usize, total_threads: usize) {
         THREAD_ID.with(|f| *f.borrow_mut() = thread);
         loop {
-            // Get a task group to run
-            while let Some(c) = task_sys.get_context() {
-                for tg in c.iter() {
-                    for chunk in tg.chunks(chunk_size) {
-                        chunk.execute(thread as i32, total_threads as i32);
-                    }
-                }
-            }
+            // Run chunks from any context with work to do
+            while task_sys.steal_chunk(thread, total_threads) {}
             // We ran out of contexts to get, so wait a bit for a new group to get launched
             // TODO: This could result in some threads remaining parked even if new contexts
             // have been launched if they're unparked then immediately park. Would be better to
@@ -226,42 +233,27 @@ impl TaskSystem for Parallel {
         //let context: &mut Context = mem::transmute(handle);
         let context: &mut Context = &mut *(handle as *mut Context);
         let thread = THREAD_ID.with(|f| *f.borrow());
-        let total_threads = num_cpus::get();
-        // Make sure all tasks are done, and execute them if not for this simple
-        // serial version. TODO: In the future we'd wait on each Group's semaphore or atomic bool
-        // Maybe the waiting thread could help execute tasks as well, otherwise it might be
-        // possible to deadlock, where all threads are waiting for some enqueue'd tasks but no
-        // threads are available to run them. Just running tasks in our context is not sufficient
-        // to prevent deadlock actually, because those tasks could in turn launch & sync and get stuck
-        // so if our tasks aren't done and there's none left to run in our context we should start
-        // running tasks from other contexts to help out
+        // This must match the count the workers pass so threadIndex is always below threadCount
+        let total_threads = self.total_threads;
+        // Help run the tasks in our own context first, the waiting thread must execute tasks
+        // as well otherwise it's possible to deadlock, where all threads are waiting for some
+        // enqueue'd tasks but no threads are available to run them.
         for tg in context.iter() {
-            for chunk in tg.chunks(self.chunk_size) {
+            for chunk in tg.chunks(crate::task_chunk_size()) {
                 // TODO: We need to figure out which thread we are
                 chunk.execute(thread as i32, total_threads as i32);
             }
         }
         // If all the tasks for this context have been finished we're done sync'ing and can
-        // clean up memory and remove the context from the TASK_LIST. Otherwise there are some
-        // unfinished groups further down the the tree that were spawned by our direct tasks that
-        // those are now sync'ing on and we need to help out. However since we don't know the tree
-        // our best option is to just start grabbing chunks from unfinished groups in the TASK_LIST
-        // and running them to at least ensure global forward progress, which will eventually get
-        // the stuff we're waiting on to finish. After each chunk execution we should check if
-        // our sync'ing context is done and break
+        // clean up memory and remove the context from the context list. Otherwise some of our
+        // tasks are still running on other threads, and may themselves be sync'ing on groups
+        // further down the tree. Since we don't know the tree we steal chunks from any context
+        // with work remaining to ensure global forward progress, which will eventually get the
+        // tasks we're waiting on to finish. A chunk is run at a time so we can check if our
+        // context is done in between and return as soon as it is.
         while !context.current_tasks_done() {
-            // Get a task group to run
-            while let Some(c) = self.get_context() {
-                let mut ran_some = false;
-                for tg in c.iter() {
-                    for chunk in tg.chunks(self.chunk_size) {
-                        ran_some = true;
-                        chunk.execute(thread as i32, total_threads as i32);
-                    }
-                }
-                if !ran_some {
-                    thread::sleep(Duration::from_millis(50));
-                }
+            if !self.steal_chunk(thread, total_threads) {
+                thread::yield_now();
             }
         }
         // Now erase this context from our vector
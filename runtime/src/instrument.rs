@@ -25,8 +25,8 @@ impl Instrument for SimpleInstrument {
         println!(
             "SimpleInstrument:\n\tFile: {}\n\tNote: {}\
                  \n\tLine: {}\n\tActive: {}\nt\tMask: 0x{:x}",
-            file.to_str().unwrap(),
-            note.to_str().unwrap(),
+            file.to_string_lossy(),
+            note.to_string_lossy(),
             line,
             active_count,
             mask
@@ -7,25 +7,42 @@
 //! This crate also includes the various runtime components for the ISPC
 //! language, including the parallel task system and performance instrumentation.
 //!
+//! The task system is enabled by the default `task-system` feature. Crates whose ISPC
+//! code doesn't `launch` tasks can disable it with `default-features = false`, which
+//! removes the task system and the `ISPCAlloc`, `ISPCLaunch` and `ISPCSync` functions
+//! ISPC code calls to launch tasks. ISPC only references these functions from code
+//! which launches tasks, so task-free kernels still link without them.
+//!
 
 #![allow(dead_code)]
 
 extern crate libc;
+#[cfg(feature = "task-system")]
 extern crate num_cpus;
 
+#[cfg(feature = "task-system")]
 pub mod exec;
 pub mod instrument;
+#[cfg(feature = "task-system")]
 pub mod task;
 
+#[cfg(feature = "task-system")]
+use std::cmp;
 use std::env;
 use std::ffi::CStr;
 use std::mem;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
+use std::process;
+#[cfg(feature = "task-system")]
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Once};
 
+#[cfg(feature = "task-system")]
 pub use crate::exec::{Parallel, TaskSystem};
 pub use crate::instrument::{Instrument, SimpleInstrument};
-pub use crate::task::ISPCTaskFn;
+#[cfg(feature = "task-system")]
+pub use crate::task::{task_alloc_stats, AllocStats, ISPCTaskFn};
 
 /// Convenience macro for generating the module to hold the raw/unsafe ISPC bindings.
 ///
@@ -42,11 +59,38 @@ pub use crate::task::ISPCTaskFn;
 /// // Functions exported from foo will be callable under foo::*
 /// ispc_module!(foo);
 /// ```
+///
+/// If the bindings were written to a different file with `Config::bindings_name`
+/// the name of the file (without the `.rs` extension) can be passed after the
+/// library name.
+///
+/// ```ignore
+/// // Bindings for foo were written to foo_bindings.rs
+/// ispc_module!(foo, "foo_bindings");
+/// ```
+///
+/// The generated module is declared `pub`, so a library crate can re-export the
+/// raw bindings to its users. The visibility can be written out to make this
+/// explicit at the import.
+///
+/// ```ignore
+/// // foo::* is part of this crate's public API
+/// ispc_module!(pub foo);
+/// ```
 #[macro_export]
 macro_rules! ispc_module {
     ($lib:ident) => {
         include!(concat!(env!("ISPC_OUT_DIR"), "/", stringify!($lib), ".rs"));
     };
+    ($lib:ident, $bindings:literal) => {
+        include!(concat!(env!("ISPC_OUT_DIR"), "/", $bindings, ".rs"));
+    };
+    (pub $lib:ident) => {
+        $crate::ispc_module!($lib);
+    };
+    (pub $lib:ident, $bindings:literal) => {
+        $crate::ispc_module!($lib, $bindings);
+    };
 }
 
 /// A `PackagedModule` refers to an ISPC module which was previously
@@ -114,9 +158,21 @@ fn get_lib_filename(libfile: &str) -> String {
     }
 }
 
+#[cfg(feature = "task-system")]
 static mut TASK_SYSTEM: Option<&'static dyn TaskSystem> = None;
+#[cfg(feature = "task-system")]
 static TASK_INIT: Once = Once::new();
 
+/// The number of worker threads for the default task system, 0 to use one per CPU
+#[cfg(feature = "task-system")]
+static TASK_THREADS: AtomicUsize = AtomicUsize::new(0);
+/// The number of tasks handed out to a thread at a time by the default task system
+#[cfg(feature = "task-system")]
+static TASK_CHUNK_SIZE: AtomicUsize = AtomicUsize::new(8);
+/// The minimum alignment of the memory allocated for task parameters
+#[cfg(feature = "task-system")]
+static TASK_ALLOC_ALIGNMENT: AtomicUsize = AtomicUsize::new(1);
+
 static mut INSTRUMENT: Option<&'static dyn Instrument> = None;
 static INSTRUMENT_INIT: Once = Once::new();
 
@@ -128,6 +184,7 @@ static INSTRUMENT_INIT: Once = Once::new();
 /// Use the function to do any extra initialization for your task system. Note that
 /// the task system will be leaked and not destroyed until the program exits and the
 /// memory space is cleaned up.
+#[cfg(feature = "task-system")]
 pub fn set_task_system<F: FnOnce() -> Arc<dyn TaskSystem>>(f: F) {
     TASK_INIT.call_once(|| {
         let task_sys = f();
@@ -139,12 +196,66 @@ pub fn set_task_system<F: FnOnce() -> Arc<dyn TaskSystem>>(f: F) {
     });
 }
 
+/// Set the number of worker threads the default `Parallel` task system will use to
+/// run tasks, by default one thread is used per CPU. This lets applications embedding
+/// ISPC code cap its parallelism to leave cores free for other work.
+///
+/// The thread pool is created the first time ISPC launches tasks, so this must be
+/// called before then. Returns false and has no effect if the task system has
+/// already been initialized, or a custom one was set with `set_task_system`.
+#[cfg(feature = "task-system")]
+pub fn set_task_threads(n: usize) -> bool {
+    if TASK_INIT.is_completed() {
+        return false;
+    }
+    TASK_THREADS.store(n, Ordering::SeqCst);
+    true
+}
+
+/// Set the number of tasks the default `Parallel` task system hands out to a thread
+/// at a time, 8 by default. Larger chunks amortize the cost of taking tasks from a
+/// launch when each task does little work, while a chunk size of 1 balances the load
+/// best when tasks are large or vary in cost. This can be changed at any time and
+/// applies to the chunks taken after the call.
+///
+/// # Panics
+/// Panics if `n` is 0.
+#[cfg(feature = "task-system")]
+pub fn set_task_chunk_size(n: usize) {
+    assert!(n > 0, "Task chunk size must be at least 1");
+    TASK_CHUNK_SIZE.store(n, Ordering::Relaxed);
+}
+
+/// Get the number of tasks handed out to a thread at a time, set with
+/// `set_task_chunk_size`. Custom task systems can use this as well.
+#[cfg(feature = "task-system")]
+pub fn task_chunk_size() -> usize {
+    TASK_CHUNK_SIZE.load(Ordering::Relaxed)
+}
+
+/// Set the minimum alignment of the memory allocated by `ISPCAlloc` for the parameters
+/// of launched tasks. ISPC requests the alignment the parameters need, this can be
+/// used to bump it up further, e.g. to 64 bytes to keep the parameters of tasks on
+/// AVX-512 targets on their own cache lines. This applies to custom task systems as well.
+///
+/// # Panics
+/// Panics if `align` is not a power of two.
+#[cfg(feature = "task-system")]
+pub fn set_task_alloc_alignment(align: usize) {
+    assert!(align.is_power_of_two(), "Alignment must be a power of two");
+    TASK_ALLOC_ALIGNMENT.store(align, Ordering::SeqCst);
+}
+
+#[cfg(feature = "task-system")]
 fn get_task_system() -> &'static dyn TaskSystem {
     // TODO: This is a bit nasty, but I'm not sure on a nicer solution. Maybe something that
     // would let the user register the desired (or default) task system? But if
     // mutable statics can't have destructors we still couldn't have an Arc or Box to something?
     TASK_INIT.call_once(|| unsafe {
-        let task_sys = Parallel::new() as Arc<dyn TaskSystem>;
+        let task_sys = match TASK_THREADS.load(Ordering::SeqCst) {
+            0 => Parallel::new(),
+            n => Parallel::with_threads(n),
+        } as Arc<dyn TaskSystem>;
         let s = &*task_sys as *const (dyn TaskSystem + 'static);
         mem::forget(task_sys);
         TASK_SYSTEM = Some(&*s);
@@ -185,6 +296,20 @@ fn get_instrument() -> &'static dyn Instrument {
     unsafe { INSTRUMENT.unwrap() }
 }
 
+/// Run `f` for a callback made by ISPC code, aborting if it panics since unwinding
+/// into the ISPC code which called us is undefined behavior. This covers panics
+/// from custom task systems and instrumenters, or from initializing them.
+fn abort_on_panic<R, F: FnOnce() -> R>(callback: &str, f: F) -> R {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(r) => r,
+        Err(_) => {
+            eprintln!("ispc_rt: panicked in {callback} called from ISPC, aborting");
+            process::abort();
+        }
+    }
+}
+
+#[cfg(feature = "task-system")]
 #[allow(non_snake_case)]
 #[doc(hidden)]
 #[no_mangle]
@@ -193,9 +318,13 @@ pub unsafe extern "C" fn ISPCAlloc(
     size: i64,
     align: i32,
 ) -> *mut libc::c_void {
-    get_task_system().alloc(handle_ptr, size, align)
+    abort_on_panic("ISPCAlloc", || {
+        let align = cmp::max(align as usize, TASK_ALLOC_ALIGNMENT.load(Ordering::Relaxed));
+        get_task_system().alloc(handle_ptr, size, align as i32)
+    })
 }
 
+#[cfg(feature = "task-system")]
 #[allow(non_snake_case)]
 #[doc(hidden)]
 #[no_mangle]
@@ -208,14 +337,17 @@ pub unsafe extern "C" fn ISPCLaunch(
     count2: libc::c_int,
 ) {
     let task_fn: ISPCTaskFn = mem::transmute(f);
-    get_task_system().launch(handle_ptr, task_fn, data, count0, count1, count2);
+    abort_on_panic("ISPCLaunch", || {
+        get_task_system().launch(handle_ptr, task_fn, data, count0, count1, count2)
+    });
 }
 
+#[cfg(feature = "task-system")]
 #[allow(non_snake_case)]
 #[doc(hidden)]
 #[no_mangle]
 pub unsafe extern "C" fn ISPCSync(handle: *mut libc::c_void) {
-    get_task_system().sync(handle);
+    abort_on_panic("ISPCSync", || get_task_system().sync(handle));
 }
 
 #[allow(non_snake_case)]
@@ -230,5 +362,7 @@ pub unsafe extern "C" fn ISPCInstrument(
     let file_name = CStr::from_ptr(cfile);
     let note = CStr::from_ptr(cnote);
     let active_count = mask.count_ones();
-    get_instrument().instrument(file_name, note, line, mask, active_count);
+    abort_on_panic("ISPCInstrument", || {
+        get_instrument().instrument(file_name, note, line, mask, active_count)
+    });
 }
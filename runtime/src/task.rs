@@ -31,6 +31,37 @@ pub type ISPCTaskFn = extern "C" fn(
     task_cnt2: libc::c_int,
 );
 
+/// Bytes allocated for task parameters since the program started
+static TOTAL_BYTES: AtomicUsize = AtomicUsize::new(0);
+/// Bytes currently allocated for task parameters by live contexts
+static LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+/// The most bytes allocated for task parameters at once
+static PEAK_BYTES: AtomicUsize = AtomicUsize::new(0);
+/// Number of allocations made for task parameters since the program started
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Statistics on the memory allocated through `ISPCAlloc` to hold the parameters
+/// of launched tasks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AllocStats {
+    /// Total number of bytes allocated
+    pub total_bytes: usize,
+    /// The most bytes which were allocated at once
+    pub peak_bytes: usize,
+    /// Number of allocations made
+    pub allocations: usize,
+}
+
+/// Get a snapshot of the memory allocated for task parameters by all contexts
+/// since the program started.
+pub fn task_alloc_stats() -> AllocStats {
+    AllocStats {
+        total_bytes: TOTAL_BYTES.load(atomic::Ordering::Relaxed),
+        peak_bytes: PEAK_BYTES.load(atomic::Ordering::Relaxed),
+        allocations: ALLOCATIONS.load(atomic::Ordering::Relaxed),
+    }
+}
+
 /// A list of all task groups spawned by a function in some launch context which
 /// will be sync'd at an explicit `sync` call or function exit.
 ///
@@ -55,6 +86,14 @@ pub struct Context {
     tasks: RwLock<Vec<Arc<Group>>>,
     /// The memory allocated for the various task group's parameters
     mem: Mutex<Vec<(AtomicPtr<libc::c_void>, std::alloc::Layout)>>,
+    /// The group to start searching from for the next chunk to hand out. This is
+    /// advanced each time a chunk is taken so threads stealing work from the context
+    /// spread out over its groups instead of all contending on the first one.
+    next_group: AtomicUsize,
+    /// Number of bytes allocated for this context's task groups
+    bytes_allocated: AtomicUsize,
+    /// Number of allocations made for this context's task groups
+    allocations: AtomicUsize,
     /// A unique identifier for this context
     pub id: usize,
 }
@@ -65,6 +104,9 @@ impl Context {
         Context {
             tasks: RwLock::new(Vec::new()),
             mem: Mutex::new(Vec::new()),
+            next_group: AtomicUsize::new(0),
+            bytes_allocated: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
             id,
         }
     }
@@ -85,6 +127,27 @@ impl Context {
     pub fn current_tasks_done(&self) -> bool {
         self.tasks.read().unwrap().iter().all(|t| t.is_finished())
     }
+    /// Check if any group in the context has tasks which haven't been given out to
+    /// a thread yet. Unlike `current_tasks_done` this is false once all the tasks are
+    /// running, even if they haven't finished.
+    pub fn has_tasks(&self) -> bool {
+        self.tasks.read().unwrap().iter().any(|t| t.has_tasks())
+    }
+    /// Take a chunk of up to `chunk_size` tasks from one of the context's groups and
+    /// execute it, returns false if there were no tasks left to take.
+    ///
+    /// This is used to steal work from contexts other than the one a thread is
+    /// waiting on, running a single chunk at a time so the thread can go back and
+    /// check on its own context in between.
+    pub fn execute_chunk(&self, chunk_size: usize, thread_id: i32, total_threads: i32) -> bool {
+        while let Some(group) = self.get_active_group() {
+            if let Some(chunk) = group.get_chunk(chunk_size) {
+                chunk.execute(thread_id, total_threads);
+                return true;
+            }
+        }
+        false
+    }
     /// Allocate some memory for this Context's task groups, returns a pointer to the allocated memory.
     ///
     /// # Safety
@@ -95,14 +158,42 @@ impl Context {
         let layout = std::alloc::Layout::from_size_align(size, align)
             .expect("std::alloc::Layout is invalid. Make sure the align is a power of 2");
         let ptr = std::alloc::alloc(layout) as *mut libc::c_void;
+        if ptr.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+        // ISPC may use aligned vector loads and stores on the parameters, so a misaligned
+        // pointer would crash or silently read the wrong data
+        debug_assert_eq!(
+            ptr as usize % align,
+            0,
+            "Task allocation is not aligned to {align} bytes"
+        );
         let mut mem = self.mem.lock().unwrap();
         mem.push((AtomicPtr::new(ptr), layout));
+
+        self.bytes_allocated
+            .fetch_add(size, atomic::Ordering::Relaxed);
+        self.allocations.fetch_add(1, atomic::Ordering::Relaxed);
+        TOTAL_BYTES.fetch_add(size, atomic::Ordering::Relaxed);
+        ALLOCATIONS.fetch_add(1, atomic::Ordering::Relaxed);
+        let live = LIVE_BYTES.fetch_add(size, atomic::Ordering::Relaxed) + size;
+        PEAK_BYTES.fetch_max(live, atomic::Ordering::Relaxed);
         ptr
     }
+    /// Get the statistics on the memory allocated for this context's task groups.
+    /// Memory is only released when the context is dropped, so the peak is the total.
+    pub fn alloc_stats(&self) -> AllocStats {
+        let bytes = self.bytes_allocated.load(atomic::Ordering::Relaxed);
+        AllocStats {
+            total_bytes: bytes,
+            peak_bytes: bytes,
+            allocations: self.allocations.load(atomic::Ordering::Relaxed),
+        }
+    }
     /// An iterator over the **current** groups in the context which have remaining tasks to
     /// run on a thread. If more task groups are added before this iterator has returned
     /// None those will appear as well.
-    pub fn iter(&self) -> ContextIter {
+    pub fn iter(&self) -> ContextIter<'_> {
         ContextIter { context: self }
     }
     /// Get a Group with tasks remaining to be executed, returns None if there
@@ -114,7 +205,12 @@ impl Context {
     /// those remaining tasks may have been taken by another threaad.
     fn get_active_group(&self) -> Option<Arc<Group>> {
         let tasks = self.tasks.read().unwrap();
-        for group in tasks.iter() {
+        if tasks.is_empty() {
+            return None;
+        }
+        let first = self.next_group.fetch_add(1, atomic::Ordering::SeqCst);
+        for i in 0..tasks.len() {
+            let group = &tasks[(first + i) % tasks.len()];
             if group.has_tasks() {
                 return Some(Arc::clone(group));
             }
@@ -138,6 +234,10 @@ impl Drop for Context {
             let m = ptr.load(atomic::Ordering::SeqCst);
             unsafe { std::alloc::dealloc(m as *mut u8, layout) };
         }
+        LIVE_BYTES.fetch_sub(
+            self.bytes_allocated.load(atomic::Ordering::Relaxed),
+            atomic::Ordering::Relaxed,
+        );
     }
 }
 
@@ -208,7 +308,7 @@ impl Group {
         }
     }
     /// Get an iterator over `chunk_size` chunks of tasks to be executed for this group
-    pub fn chunks(&self, chunk_size: usize) -> GroupChunks {
+    pub fn chunks(&self, chunk_size: usize) -> GroupChunks<'_> {
         GroupChunks {
             group: self,
             chunk_size,
@@ -234,7 +334,7 @@ impl Group {
     /// though you may get fewer if there aren't that many tasks left. If the chunk
     /// you get is the last chunk to be executed (`chunk.end == total.0 * total.1 * total.2`)
     /// you must mark this group as finished upon completing execution of the chunk
-    fn get_chunk(&self, desired_tasks: usize) -> Option<Chunk> {
+    fn get_chunk(&self, desired_tasks: usize) -> Option<Chunk<'_>> {
         let start = self
             .start
             .fetch_add(desired_tasks, atomic::Ordering::SeqCst);
@@ -0,0 +1,124 @@
+//! Tests that panics in the callbacks ISPC code makes into the runtime abort the
+//! process instead of unwinding into the ISPC code. Each test runs itself again in
+//! a child process which makes the callback, and checks how the child exited.
+#![cfg(feature = "task-system")]
+
+use std::env;
+use std::process::{Command, Output};
+use std::ptr;
+use std::sync::Arc;
+
+use ispc_rt::{Parallel, TaskSystem};
+
+/// Set in the child process to the name of the test it should run
+const CHILD_VAR: &str = "ISPC_RT_PANIC_TEST_CHILD";
+
+/// A task system which panics when tasks are launched
+struct PanickingLaunch(Arc<Parallel>);
+
+impl TaskSystem for PanickingLaunch {
+    unsafe fn alloc(
+        &self,
+        handle_ptr: *mut *mut libc::c_void,
+        size: i64,
+        align: i32,
+    ) -> *mut libc::c_void {
+        self.0.alloc(handle_ptr, size, align)
+    }
+    unsafe fn launch(
+        &self,
+        _handle_ptr: *mut *mut libc::c_void,
+        _f: ispc_rt::ISPCTaskFn,
+        _data: *mut libc::c_void,
+        _count0: i32,
+        _count1: i32,
+        _count2: i32,
+    ) {
+        panic!("launch failed");
+    }
+    unsafe fn sync(&self, handle: *mut libc::c_void) {
+        self.0.sync(handle)
+    }
+}
+
+extern "C" fn panicking_task(
+    _data: *mut libc::c_void,
+    _thread_idx: libc::c_int,
+    _thread_cnt: libc::c_int,
+    _task_idx: libc::c_int,
+    _task_cnt: libc::c_int,
+    _task_idx0: libc::c_int,
+    _task_idx1: libc::c_int,
+    _task_idx2: libc::c_int,
+    _task_cnt0: libc::c_int,
+    _task_cnt1: libc::c_int,
+    _task_cnt2: libc::c_int,
+) {
+    panic!("task failed");
+}
+
+/// Launch a single `panicking_task` and wait for it, like ISPC code running
+/// `launch panicking_task(); sync;` does
+fn launch_panicking_task() {
+    let mut handle = ptr::null_mut();
+    unsafe {
+        let data = ispc_rt::ISPCAlloc(&mut handle, 8, 8);
+        ispc_rt::ISPCLaunch(
+            &mut handle,
+            panicking_task as *mut libc::c_void,
+            data,
+            1,
+            1,
+            1,
+        );
+        ispc_rt::ISPCSync(handle);
+    }
+}
+
+/// Run the test `name` in a child process, or return None if this is the child
+fn run_child(name: &str) -> Option<Output> {
+    if env::var(CHILD_VAR).as_deref() == Ok(name) {
+        return None;
+    }
+    let output = Command::new(env::current_exe().unwrap())
+        .args([name, "--exact", "--nocapture", "--test-threads=1"])
+        .env(CHILD_VAR, name)
+        .output()
+        .unwrap();
+    Some(output)
+}
+
+/// Check that the child process was aborted, rather than exiting normally
+/// after the panic unwound through the callback
+fn assert_aborted(output: &Output) {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success(), "{stderr}");
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        assert_eq!(output.status.signal(), Some(libc::SIGABRT), "{stderr}");
+    }
+}
+
+#[test]
+fn panicking_task_system_aborts() {
+    let Some(output) = run_child("panicking_task_system_aborts") else {
+        ispc_rt::set_task_system(|| Arc::new(PanickingLaunch(Parallel::new())));
+        launch_panicking_task();
+        return;
+    };
+    assert_aborted(&output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("panicked in ISPCLaunch"), "{stderr}");
+}
+
+#[test]
+fn panicking_task_aborts() {
+    let Some(output) = run_child("panicking_task_aborts") else {
+        launch_panicking_task();
+        return;
+    };
+    assert_aborted(&output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("task failed"), "{stderr}");
+}
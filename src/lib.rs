@@ -205,9 +205,36 @@ pub use ispc_rt::*;
 /// // Functions exported from foo will be callable under foo::*
 /// ispc_module!(foo);
 /// ```
+///
+/// If the bindings were written to a different file with `Config::bindings_name`
+/// the name of the file (without the `.rs` extension) can be passed after the
+/// library name.
+///
+/// ```ignore
+/// // Bindings for foo were written to foo_bindings.rs
+/// ispc_module!(foo, "foo_bindings");
+/// ```
+///
+/// The generated module is declared `pub`, so a library crate can re-export the
+/// raw bindings to its users. The visibility can be written out to make this
+/// explicit at the import.
+///
+/// ```ignore
+/// // foo::* is part of this crate's public API
+/// ispc_module!(pub foo);
+/// ```
 #[macro_export]
 macro_rules! ispc_module {
     ($lib:ident) => {
         include!(concat!(env!("ISPC_OUT_DIR"), "/", stringify!($lib), ".rs"));
     };
+    ($lib:ident, $bindings:literal) => {
+        include!(concat!(env!("ISPC_OUT_DIR"), "/", $bindings, ".rs"));
+    };
+    (pub $lib:ident) => {
+        $crate::ispc_module!($lib);
+    };
+    (pub $lib:ident, $bindings:literal) => {
+        $crate::ispc_module!($lib, $bindings);
+    };
 }